@@ -0,0 +1,187 @@
+/*
+ * cloze.rs -- Cloze deletion markup
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+/// Replaces a hidden cloze without a hint.
+pub const MASK: &str = "[...]";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),
+    Cloze {
+        number: u32,
+        text: &'a str,
+        hint: Option<&'a str>,
+    },
+}
+
+/// Parses a cloze at the start of `s`.
+/// Returns the cloze and the length of its markup.
+fn parse_cloze(s: &str) -> Option<(Segment<'_>, usize)> {
+    let rest = s.strip_prefix("{{c")?;
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let number = rest[..digits].parse().ok()?;
+    let body = rest[digits..].strip_prefix("::")?;
+    let end = body.find("}}")?;
+    if body[..end].contains("{{") {
+        return None;
+    }
+    let (text, hint) = match body[..end].split_once("::") {
+        Some((text, hint)) => (text, Some(hint)),
+        None => (&body[..end], None),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    let len = s.len() - body.len() + end + 2;
+    Some((Segment::Cloze { number, text, hint }, len))
+}
+
+/// A cloze is marked as `{{c1::word}}` or `{{c1::word::hint}}`.
+/// Malformed markup and empty clozes are left as plain text.
+fn parse(text: &str) -> Vec<Segment<'_>> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while let Some(off) = text[pos..].find("{{c") {
+        pos += off;
+        match parse_cloze(&text[pos..]) {
+            Some((cloze, len)) => {
+                if start < pos {
+                    result.push(Segment::Text(&text[start..pos]));
+                }
+                result.push(cloze);
+                pos += len;
+                start = pos;
+            }
+            None => pos += 3,
+        }
+    }
+    if start < text.len() {
+        result.push(Segment::Text(&text[start..]));
+    }
+    result
+}
+
+/// The numbers of the clozes in the text, ascending and without repetition.
+pub fn numbers(text: &str) -> Vec<u32> {
+    let mut result: Vec<u32> = parse(text)
+        .into_iter()
+        .filter_map(|x| match x {
+            Segment::Cloze { number, .. } => Some(number),
+            Segment::Text(_) => None,
+        })
+        .collect();
+    result.sort_unstable();
+    result.dedup();
+    result
+}
+
+/// Hides the clozes of the given number and reveals the others.
+/// Returns the masked text and the hidden text;
+/// several hidden clozes are joined by `, `.
+pub fn mask(text: &str, number: u32) -> (String, String) {
+    let mut masked = String::new();
+    let mut hidden = Vec::new();
+    for segment in parse(text) {
+        match segment {
+            Segment::Text(x) => masked.push_str(x),
+            Segment::Cloze {
+                number: n,
+                text,
+                hint,
+            } if n == number => {
+                match hint {
+                    Some(hint) => {
+                        masked.push('[');
+                        masked.push_str(hint);
+                        masked.push(']');
+                    }
+                    None => masked.push_str(MASK),
+                }
+                hidden.push(text);
+            }
+            Segment::Cloze { text, .. } => masked.push_str(text),
+        }
+    }
+    (masked, hidden.join(", "))
+}
+
+/// The text with all clozes revealed.
+pub fn reveal(text: &str) -> String {
+    parse(text)
+        .into_iter()
+        .map(|x| match x {
+            Segment::Text(text) | Segment::Cloze { text, .. } => text,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_sorted_and_unique() {
+        assert_eq!(numbers("{{c2::b}} {{c1::a}} {{c2::c}}"), [1, 2]);
+        assert!(numbers("no clozes").is_empty());
+    }
+
+    #[test]
+    fn mask_hides_one_number_and_reveals_the_rest() {
+        let text = "{{c1::Labas}} {{c2::rytas::time of day}}!";
+        assert_eq!(
+            mask(text, 1),
+            (String::from("[...] rytas!"), String::from("Labas"))
+        );
+        assert_eq!(
+            mask(text, 2),
+            (String::from("Labas [time of day]!"), String::from("rytas"))
+        );
+    }
+
+    #[test]
+    fn repeated_number_is_hidden_together() {
+        let (masked, hidden) = mask("{{c1::labas}}, {{c1::labas}}!", 1);
+        assert_eq!(masked, "[...], [...]!");
+        assert_eq!(hidden, "labas, labas");
+    }
+
+    #[test]
+    fn malformed_markup_is_plain_text() {
+        for text in [
+            "{{c::x}}",
+            "{{c1:x}}",
+            "{{cx::x}}",
+            "{{c1::x",
+            "{{c1::}}",
+            "{{c1:: }}",
+        ] {
+            assert!(numbers(text).is_empty(), "{}", text);
+            assert_eq!(reveal(text), text);
+        }
+    }
+
+    #[test]
+    fn malformed_markup_does_not_hide_a_later_cloze() {
+        let text = "{{c1:: ž {{c1::žodis}}";
+        assert_eq!(numbers(text), [1]);
+        assert_eq!(mask(text, 1).0, "{{c1:: ž [...]");
+        assert_eq!(reveal(text), "{{c1:: ž žodis");
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * diff.rs -- Alignment of the user input with the expected answer
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use serde::{Deserialize, Serialize};
+
+/// A single step of the alignment.
+/// Every step holds one unit (a character or a word).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiffOp {
+    Equal(String),
+    /// Missing from the input.
+    Insert(String),
+    /// Present in the input only.
+    Delete(String),
+    Substitute {
+        input: String,
+        answer: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnswerDiff {
+    /// Position of the compared answer within `TableEntry::answers`.
+    pub answer: usize,
+    pub chars: Vec<DiffOp>,
+    /// Alignment of the whitespace-separated words.
+    pub words: Vec<DiffOp>,
+}
+
+impl AnswerDiff {
+    pub fn new(input: &str, answer: &str, idx: usize) -> AnswerDiff {
+        AnswerDiff {
+            answer: idx,
+            chars: diff_chars(input, answer),
+            words: diff_words(input, answer),
+        }
+    }
+}
+
+/// Finds the alignment with the least number of
+/// insertions, deletions and substitutions (Levenshtein distance).
+fn align(a: &[String], b: &[String]) -> Vec<DiffOp> {
+    let (n, m) = (a.len(), b.len());
+    let mut d = vec![vec![0usize; m + 1]; n + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=m).collect();
+    for i in 1..=n {
+        for j in 1..=m {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+        }
+    }
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && d[i][j] == d[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]) {
+            ops.push(if a[i - 1] == b[j - 1] {
+                DiffOp::Equal(a[i - 1].clone())
+            } else {
+                DiffOp::Substitute {
+                    input: a[i - 1].clone(),
+                    answer: b[j - 1].clone(),
+                }
+            });
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && d[i][j] == d[i - 1][j] + 1 {
+            ops.push(DiffOp::Delete(a[i - 1].clone()));
+            i -= 1;
+        } else {
+            ops.push(DiffOp::Insert(b[j - 1].clone()));
+            j -= 1;
+        }
+    }
+    ops.reverse();
+    ops
+}
+
+pub fn diff_chars(input: &str, answer: &str) -> Vec<DiffOp> {
+    let units = |s: &str| s.chars().map(String::from).collect::<Vec<String>>();
+    align(&units(input), &units(answer))
+}
+
+pub fn diff_words(input: &str, answer: &str) -> Vec<DiffOp> {
+    let units = |s: &str| {
+        s.split_whitespace()
+            .map(String::from)
+            .collect::<Vec<String>>()
+    };
+    align(&units(input), &units(answer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(x: &str) -> String {
+        String::from(x)
+    }
+
+    #[test]
+    fn equal_strings_align_unchanged() {
+        let ops = diff_chars("namas", "namas");
+        assert_eq!(ops.len(), 5);
+        assert!(ops.iter().all(|x| matches!(x, DiffOp::Equal(_))));
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(
+            diff_chars("cat", "cut"),
+            [
+                DiffOp::Equal(s("c")),
+                DiffOp::Substitute {
+                    input: s("a"),
+                    answer: s("u"),
+                },
+                DiffOp::Equal(s("t")),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_extra_characters() {
+        assert_eq!(
+            diff_chars("hose", "house"),
+            [
+                DiffOp::Equal(s("h")),
+                DiffOp::Equal(s("o")),
+                DiffOp::Insert(s("u")),
+                DiffOp::Equal(s("s")),
+                DiffOp::Equal(s("e")),
+            ]
+        );
+        assert_eq!(
+            diff_chars("cart", "car"),
+            [
+                DiffOp::Equal(s("c")),
+                DiffOp::Equal(s("a")),
+                DiffOp::Equal(s("r")),
+                DiffOp::Delete(s("t")),
+            ]
+        );
+    }
+
+    #[test]
+    fn transposition_is_two_edits() {
+        let ops = diff_chars("ab", "ba");
+        let edits = ops
+            .iter()
+            .filter(|x| !matches!(x, DiffOp::Equal(_)))
+            .count();
+        assert_eq!(edits, 2);
+    }
+
+    #[test]
+    fn empty_input_inserts_everything() {
+        assert_eq!(diff_chars("", "ž"), [DiffOp::Insert(s("ž"))]);
+        assert!(diff_chars("", "").is_empty());
+    }
+
+    #[test]
+    fn words_ignore_extra_whitespace() {
+        assert_eq!(
+            diff_words("good  evening ", "good morning"),
+            [
+                DiffOp::Equal(s("good")),
+                DiffOp::Substitute {
+                    input: s("evening"),
+                    answer: s("morning"),
+                },
+            ]
+        );
+    }
+}
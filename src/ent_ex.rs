@@ -17,8 +17,15 @@
  *
  */
 
+use crate::clock::{Clock, SystemClock, Timestamp};
+use crate::cloze;
+use crate::grade::{damerau_levenshtein, TypoTolerance, Verdict};
+use crate::norm::AnswerNormalizer;
 use crate::ostree::OSTree;
+use crate::sched::{DistrustScheduler, FsrsState, SchedContext, Scheduler, Sm2State};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
@@ -74,12 +81,183 @@ pub struct ScoreArgs {
 #[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TableEntry {
     pub lhs: String,
+    /// The canonical answer, which is displayed to the user.
     pub rhs: String,
+    /// Other accepted answers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<String>,
+    /// Which side of a deck entry the card asks for.
+    /// Reverse cards are made by `make_cards`.
+    #[serde(default, skip_serializing_if = "Side::is_forward")]
+    pub side: Side,
+    /// The number of the cloze hidden by the card:
+    /// `lhs` is the masked text and `rhs` the hidden text.
+    /// Cloze cards are made by `make_cards`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cloze: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    /// Asks for `rhs` given `lhs`.
+    #[default]
+    Forward,
+    /// Asks for `lhs` given `rhs`.
+    Reverse,
+}
+
+impl Side {
+    pub fn is_forward(&self) -> bool {
+        *self == Side::Forward
+    }
+}
+
+/// The direction in which a deck is drilled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    #[default]
+    Forward,
+    Reverse,
+    Both,
+}
+
+/// Makes the cards to drill from the deck entries.
+/// A reverse card swaps the sides of the entry:
+/// it shows the canonical `rhs` and expects `lhs`.
+/// With `Direction::Both`, the forward cards come first,
+/// followed by the reverse cards in the same order.
+///
+/// An entry whose `lhs` contains clozes (e.g. `{{c1::word}}`, see `cloze::mask`)
+/// makes a card for every cloze number instead, in either direction;
+/// the `rhs` of such an entry is not drilled.
+pub fn make_cards(entries: &[TableEntry], direction: Direction) -> Vec<TableEntry> {
+    let clozes = |x: &TableEntry| -> Vec<TableEntry> {
+        cloze::numbers(&x.lhs)
+            .into_iter()
+            .map(|n| {
+                let (lhs, rhs) = cloze::mask(&x.lhs, n);
+                TableEntry {
+                    lhs,
+                    rhs,
+                    alternatives: Vec::new(),
+                    side: Side::Forward,
+                    cloze: Some(n),
+                }
+            })
+            .collect()
+    };
+    let forward = |x: &TableEntry| match clozes(x) {
+        cards if cards.is_empty() => vec![x.clone()],
+        cards => cards,
+    };
+    let reverse = |x: &TableEntry| TableEntry {
+        lhs: x.rhs.clone(),
+        rhs: x.lhs.clone(),
+        alternatives: Vec::new(),
+        side: Side::Reverse,
+        cloze: None,
+    };
+    let reverse_or_clozes = |x: &TableEntry| match clozes(x) {
+        cards if cards.is_empty() => vec![reverse(x)],
+        cards => cards,
+    };
+    match direction {
+        Direction::Forward => entries.iter().flat_map(forward).collect(),
+        Direction::Reverse => entries.iter().flat_map(reverse_or_clozes).collect(),
+        Direction::Both => entries
+            .iter()
+            .flat_map(forward)
+            .chain(
+                entries
+                    .iter()
+                    .filter(|x| cloze::numbers(&x.lhs).is_empty())
+                    .map(reverse),
+            )
+            .collect(),
+    }
+}
+
+/// Identifies the progress record of a card.
+/// Alternative answers are left out,
+/// so that adding a synonym to a deck keeps the progress.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct CardKey<'a> {
+    lhs: &'a str,
+    rhs: &'a str,
+    side: Side,
+    cloze: Option<u32>,
 }
 
 impl TableEntry {
-    pub fn assess(&self, user_input: String) -> bool {
-        user_input == self.rhs
+    fn key(&self) -> CardKey<'_> {
+        CardKey {
+            lhs: &self.lhs,
+            rhs: &self.rhs,
+            side: self.side,
+            cloze: self.cloze,
+        }
+    }
+
+    /// All accepted answers, the canonical one first.
+    pub fn answers(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.rhs).chain(self.alternatives.iter())
+    }
+
+    /// Compares the input with the answers byte by byte.
+    pub fn assess(&self, user_input: String) -> Verdict {
+        self.assess_with(
+            &user_input,
+            &AnswerNormalizer::default(),
+            &TypoTolerance::default(),
+        )
+    }
+
+    /// Compares the input with the answers after normalisation.
+    /// If no answer matches exactly, the closest one within the tolerance is accepted.
+    pub fn assess_with(
+        &self,
+        user_input: &str,
+        norm: &AnswerNormalizer,
+        tolerance: &TypoTolerance,
+    ) -> Verdict {
+        let input = norm.normalize(user_input);
+        let answers: Vec<String> = self.answers().map(|x| norm.normalize(x)).collect();
+        if let Some(answer) = answers.iter().position(|x| *x == input) {
+            return Verdict::Exact { answer };
+        }
+        answers
+            .iter()
+            .enumerate()
+            .map(|(answer, x)| (answer, damerau_levenshtein(&input, x), tolerance.allowed(x)))
+            .filter(|&(_, distance, allowed)| distance <= allowed)
+            .min_by_key(|&(_, distance, _)| distance)
+            .map_or(Verdict::Wrong, |(answer, distance, _)| {
+                Verdict::AcceptedWithTypo { answer, distance }
+            })
+    }
+}
+
+/// A table together with the deck metadata.
+#[derive(Clone, Debug, Default)]
+pub struct Deck {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Language code of the `lhs` column (e.g. `lt`).
+    pub source_language: Option<String>,
+    /// Language code of the `rhs` column (e.g. `en`).
+    pub target_language: Option<String>,
+    pub author: Option<String>,
+    pub license: Option<String>,
+    /// Score arguments suggested for new progress tables.
+    pub score_args: Option<ScoreArgs>,
+    /// Answer normalisation suggested for the simulation.
+    pub normalizer: Option<AnswerNormalizer>,
+    pub entries: Vec<TableEntry>,
+}
+
+impl Deck {
+    pub fn cards(&self, direction: Direction) -> Vec<TableEntry> {
+        make_cards(&self.entries, direction)
     }
 }
 
@@ -94,6 +272,9 @@ pub struct ProgressTableView {
     pub entries: Vec<(ProgressEntry, TableEntry)>,
     pub age: i32,
     pub score_args: ScoreArgs,
+    /// See `ProgressTable::get_leitner_round`.
+    #[serde(default)]
+    pub leitner_round: u32,
 }
 
 impl ProgressTableView {
@@ -101,11 +282,13 @@ impl ProgressTableView {
         ProgressTableView {
             age: table.age,
             score_args: table.score_args,
+            leitner_round: table.leitner_round,
             entries: table
                 .entries
                 .iter()
                 .zip(te)
                 .map(|(&x, y)| (x, y.clone()))
+                .chain(table.dormant.iter().cloned())
                 .collect(),
         }
     }
@@ -122,6 +305,12 @@ pub struct ProgressTable {
     tree_failed: OSTree,
     age: i32,
     score_args: ScoreArgs,
+    leitner_round: u32,
+    /// Records of the progress file which are not in the current table
+    /// (e.g. cards of the other direction), kept to be written back.
+    dormant: Vec<(ProgressEntry, TableEntry)>,
+    scheduler: Box<dyn Scheduler>,
+    clock: Box<dyn Clock>,
 }
 
 pub struct UnitConstants {}
@@ -130,15 +319,107 @@ pub const UNIT: Score = Score(10000);
 
 pub struct OutOfRangeError;
 
+/// Score arguments implied by the legacy (`stp`-based) progress files.
+const LEGACY_SCORE_ARGS: ScoreArgs = ScoreArgs {
+    degrade_factor: 0.8,
+    origin: Score(10000),
+    target: Score(100),
+};
+
+#[derive(Debug)]
+pub enum ProgressLoadError {
+    Io(std::io::Error),
+    /// The file is not a valid JSON document.
+    NotJson(serde_json::Error),
+    /// The file is JSON, but matches neither the current nor the legacy schema.
+    UnknownSchema(serde_json::Error),
+    /// The legacy `stp` value cannot be converted to an age.
+    LegacyStpOutOfRange(f64),
+}
+
+impl fmt::Display for ProgressLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressLoadError::Io(e) => write!(f, "cannot read progress file: {}", e),
+            ProgressLoadError::NotJson(e) => write!(f, "progress file is not valid JSON: {}", e),
+            ProgressLoadError::UnknownSchema(e) => {
+                write!(f, "unknown progress file schema: {}", e)
+            }
+            ProgressLoadError::LegacyStpOutOfRange(stp) => {
+                write!(f, "legacy progress value out of range: {}", stp)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProgressLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProgressLoadError::Io(e) => Some(e),
+            ProgressLoadError::NotJson(e) | ProgressLoadError::UnknownSchema(e) => Some(e),
+            ProgressLoadError::LegacyStpOutOfRange(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProgressLoadError {
+    fn from(e: std::io::Error) -> Self {
+        ProgressLoadError::Io(e)
+    }
+}
+
+/// Outcome of `ProgressTable::new_from_file_lenient`.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// Positions of the dropped records in the `entries` array of the file.
+    pub dropped: Vec<usize>,
+    /// The `entries` array ended prematurely.
+    pub truncated: bool,
+    /// The age and score arguments were not readable and were replaced
+    /// by age 0 and the fallback score arguments.
+    /// They are written after the `entries` array,
+    /// so this is also set whenever the file is truncated.
+    pub header_missing: bool,
+}
+
 impl ProgressTable {
-    fn tree_from_entries(entries: &[ProgressEntry], pass: bool) -> OSTree {
-        let mut tree = OSTree::new(entries.len());
-        for (idx, &entry) in entries.iter().enumerate() {
-            if entry.pass == pass {
-                tree.assign(idx, entry.distrust.0);
+    fn context(&self) -> SchedContext {
+        SchedContext {
+            age: self.age,
+            unit: self.unit_score(),
+            now: self.clock.now(),
+        }
+    }
+
+    /// Recomputes the sampling weights of all entries.
+    fn rebuild_trees(&mut self) {
+        let ctx = self.context();
+        self.tree_passed = OSTree::new(self.capacity);
+        self.tree_failed = OSTree::new(self.capacity);
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let w = self.scheduler.weight(entry, &ctx);
+            if entry.pass {
+                self.tree_passed.assign(idx, w);
+            } else {
+                self.tree_failed.assign(idx, w);
             }
         }
-        tree
+    }
+
+    /// Replaces the source of the timestamps (`SystemClock` by default).
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Replaces the scheduling algorithm (`DistrustScheduler` by default).
+    /// The entries are prepared for the new algorithm (see `Scheduler::prepare`).
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.scheduler = scheduler;
+        let ctx = self.context();
+        for entry in self.entries.iter_mut() {
+            self.scheduler.prepare(entry, &ctx);
+        }
+        self.rebuild_trees();
     }
 
     pub fn is_partial(&self) -> bool {
@@ -165,55 +446,184 @@ impl ProgressTable {
         self.entries.is_empty()
     }
 
-    fn migrate(buf: &[u8]) -> ProgressTableView {
-        let data: ProgressTableViewLegacy = serde_json::from_slice(buf).unwrap();
-        const SCORE_ARGS: ScoreArgs = ScoreArgs {
-            degrade_factor: 0.8,
-            origin: Score(10000),
-            target: Score(100),
-        };
-        ProgressTableView {
-            score_args: SCORE_ARGS,
-            age: Score::inverse(data.stp, data.entries.len() as f64, &SCORE_ARGS).unwrap() as i32,
+    fn migrate(
+        buf: &[u8],
+        view_err: serde_json::Error,
+    ) -> Result<ProgressTableView, ProgressLoadError> {
+        let data: ProgressTableViewLegacy =
+            serde_json::from_slice(buf).map_err(|_| ProgressLoadError::UnknownSchema(view_err))?;
+        let age = Score::inverse(data.stp, data.entries.len() as f64, &LEGACY_SCORE_ARGS)
+            .ok_or(ProgressLoadError::LegacyStpOutOfRange(data.stp))?;
+        Ok(ProgressTableView {
+            score_args: LEGACY_SCORE_ARGS,
+            age: age as i32,
+            leitner_round: 0,
             entries: data.entries,
-        }
+        })
     }
 
-    pub fn new_from_file(entries: &[TableEntry], path: &Path) -> ProgressTable {
-        use std::collections::HashMap;
-        let mut buf = Vec::<u8>::new();
-        File::open(path).unwrap().read_to_end(&mut buf).unwrap();
-        let data: ProgressTableView =
-            serde_json::from_slice(&buf).unwrap_or_else(|_| Self::migrate(&buf));
-        let mut imap = HashMap::new();
-        for entry in data.entries {
-            imap.insert(entry.1, entry.0);
+    fn parse_view(buf: &[u8]) -> Result<ProgressTableView, ProgressLoadError> {
+        serde_json::from_slice::<serde_json::Value>(buf).map_err(ProgressLoadError::NotJson)?;
+        serde_json::from_slice(buf).or_else(|e| Self::migrate(buf, e))
+    }
+
+    /// Reads the header (`age`, `score_args` and `leitner_round`) of a progress file
+    /// regardless of the state of its entries.
+    fn parse_header(buf: &[u8]) -> Option<(i32, ScoreArgs, u32)> {
+        let value: serde_json::Value = serde_json::from_slice(buf).ok()?;
+        let current = || -> Option<(i32, ScoreArgs, u32)> {
+            let age = serde_json::from_value(value.get("age")?.clone()).ok()?;
+            let sa = serde_json::from_value(value.get("score_args")?.clone()).ok()?;
+            let round = value
+                .get("leitner_round")
+                .and_then(|x| serde_json::from_value(x.clone()).ok())
+                .unwrap_or(0);
+            Some((age, sa, round))
+        };
+        let legacy = || -> Option<(i32, ScoreArgs, u32)> {
+            let stp = value.get("stp")?.as_f64()?;
+            let n = value.get("entries")?.as_array()?.len();
+            let age = Score::inverse(stp, n as f64, &LEGACY_SCORE_ARGS)?;
+            Some((age as i32, LEGACY_SCORE_ARGS, 0))
+        };
+        current().or_else(legacy)
+    }
+
+    /// Reads the records of the `entries` array one by one,
+    /// so that a damaged record or a truncated file
+    /// does not invalidate the preceding records.
+    fn salvage_entries(buf: &[u8], report: &mut LoadReport) -> Vec<(ProgressEntry, TableEntry)> {
+        use serde_json::Value;
+        const KEY: &[u8] = b"\"entries\"";
+        let skip_ws = |mut pos: usize| {
+            while pos < buf.len() && buf[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            pos
+        };
+        let mut result = Vec::new();
+        let start = buf
+            .windows(KEY.len())
+            .position(|w| w == KEY)
+            .map(|p| skip_ws(p + KEY.len()))
+            .filter(|&p| buf.get(p) == Some(&b':'))
+            .map(|p| skip_ws(p + 1))
+            .filter(|&p| buf.get(p) == Some(&b'['));
+        let mut pos = match start {
+            Some(p) => p + 1,
+            None => {
+                report.truncated = true;
+                return result;
+            }
+        };
+        let mut index = 0;
+        loop {
+            pos = skip_ws(pos);
+            match buf.get(pos) {
+                Some(b']') => break,
+                Some(b',') if index > 0 => pos = skip_ws(pos + 1),
+                _ if index == 0 => (),
+                _ => {
+                    report.truncated = true;
+                    break;
+                }
+            }
+            let mut stream = serde_json::Deserializer::from_slice(&buf[pos..]).into_iter::<Value>();
+            match stream.next() {
+                Some(Ok(value)) => {
+                    match serde_json::from_value::<(ProgressEntry, TableEntry)>(value) {
+                        Ok(record) => result.push(record),
+                        Err(_) => report.dropped.push(index),
+                    }
+                    pos += stream.byte_offset();
+                    index += 1;
+                }
+                _ => {
+                    report.truncated = true;
+                    break;
+                }
+            }
         }
+        result
+    }
+
+    fn from_view(entries: &[TableEntry], data: ProgressTableView) -> ProgressTable {
+        use std::collections::{HashMap, HashSet};
+        let current: HashSet<CardKey> = entries.iter().map(TableEntry::key).collect();
+        let (kept, dormant): (Vec<_>, Vec<_>) = data
+            .entries
+            .into_iter()
+            .partition(|entry| current.contains(&entry.1.key()));
+        let imap: HashMap<CardKey, ProgressEntry> =
+            kept.iter().map(|(pe, te)| (te.key(), *pe)).collect();
         let n = entries.len();
         let pe = || {
             entries.iter().map(|x| {
-                if imap.contains_key(x) {
-                    imap[x]
+                if let Some(&pe) = imap.get(&x.key()) {
+                    pe
                 } else {
-                    ProgressEntry {
-                        distrust: Score(
-                            Score::function(data.age, n as f64, &data.score_args) as i64
-                        ),
-                        pass: false,
-                    }
+                    ProgressEntry::new(Score(
+                        Score::function(data.age, n as f64, &data.score_args) as i64
+                    ))
                 }
             })
         };
         let pev: Vec<ProgressEntry> = pe().collect();
-        ProgressTable {
-            entries: pev.clone(),
-            capacity: entries.len(),
+        let mut table = ProgressTable {
             cnt_failed: pev.iter().filter(|x: &&ProgressEntry| !x.pass).count(),
-            tree_passed: ProgressTable::tree_from_entries(&pev, true),
-            tree_failed: ProgressTable::tree_from_entries(&pev, false),
+            entries: pev,
+            capacity: entries.len(),
+            tree_passed: OSTree::new(0),
+            tree_failed: OSTree::new(0),
             age: data.age,
             score_args: data.score_args,
-        }
+            leitner_round: data.leitner_round,
+            dormant,
+            scheduler: Box::new(DistrustScheduler),
+            clock: Box::new(SystemClock),
+        };
+        table.rebuild_trees();
+        table
+    }
+
+    pub fn new_from_file(
+        entries: &[TableEntry],
+        path: &Path,
+    ) -> Result<ProgressTable, ProgressLoadError> {
+        let mut buf = Vec::<u8>::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let data = Self::parse_view(&buf)?;
+        Ok(Self::from_view(entries, data))
+    }
+
+    /// Same as `new_from_file`, but recovers what it can from a damaged file.
+    /// Records which cannot be read are dropped and listed in the report.
+    /// If the header cannot be read, age 0 and `fallback` are used instead.
+    pub fn new_from_file_lenient(
+        entries: &[TableEntry],
+        path: &Path,
+        fallback: ScoreArgs,
+    ) -> Result<(ProgressTable, LoadReport), ProgressLoadError> {
+        let mut buf = Vec::<u8>::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let mut report = LoadReport::default();
+        let data = match Self::parse_view(&buf) {
+            Ok(data) => data,
+            Err(_) => {
+                let (age, score_args, leitner_round) =
+                    Self::parse_header(&buf).unwrap_or_else(|| {
+                        report.header_missing = true;
+                        (0, fallback, 0)
+                    });
+                ProgressTableView {
+                    entries: Self::salvage_entries(&buf, &mut report),
+                    age,
+                    score_args,
+                    leitner_round,
+                }
+            }
+        };
+        Ok((Self::from_view(entries, data), report))
     }
 
     pub fn new(entries: Pin<Arc<Vec<TableEntry>>>, score_args: ScoreArgs) -> ProgressTable {
@@ -230,6 +640,10 @@ impl ProgressTable {
             tree_failed: OSTree::new(capacity),
             age,
             score_args,
+            leitner_round: 0,
+            dormant: Vec::new(),
+            scheduler: Box::new(DistrustScheduler),
+            clock: Box::new(SystemClock),
         }
     }
 
@@ -242,13 +656,7 @@ impl ProgressTable {
         let n = entries.len();
         let unit = Score(Score::function(age, n as f64, &score_args) as i64);
         ProgressTable {
-            entries: vec![
-                ProgressEntry {
-                    distrust: unit,
-                    pass: false,
-                };
-                n
-            ],
+            entries: vec![ProgressEntry::new(unit); n],
             capacity,
             cnt_failed: n,
             tree_passed: OSTree::new(capacity),
@@ -261,6 +669,10 @@ impl ProgressTable {
             },
             age,
             score_args,
+            leitner_round: 0,
+            dormant: Vec::new(),
+            scheduler: Box::new(DistrustScheduler),
+            clock: Box::new(SystemClock),
         }
     }
 
@@ -270,11 +682,13 @@ impl ProgressTable {
         if n + m > self.capacity {
             Err(OutOfRangeError)
         } else {
+            let ctx = self.context();
             for (i, pe) in chunk.iter().enumerate() {
+                let w = self.scheduler.weight(pe, &ctx);
                 if pe.pass {
-                    self.tree_passed.assign(i + n, pe.distrust.0);
+                    self.tree_passed.assign(i + n, w);
                 } else {
-                    self.tree_failed.assign(i + n, pe.distrust.0);
+                    self.tree_failed.assign(i + n, w);
                     self.cnt_failed += 1;
                 }
             }
@@ -284,10 +698,27 @@ impl ProgressTable {
         }
     }
 
-    pub fn select_random_entries<F>(&mut self, n: usize, pass: bool, mut selector: F) -> Vec<usize>
+    pub fn select_random_entries<F>(&mut self, n: usize, pass: bool, selector: F) -> Vec<usize>
+    where
+        F: FnMut() -> f64,
+    {
+        self.select_random_entries_except(n, pass, &BTreeSet::new(), selector)
+    }
+
+    /// Like `select_random_entries`, but never selects the excluded entries.
+    pub fn select_random_entries_except<F>(
+        &mut self,
+        n: usize,
+        pass: bool,
+        excluded: &BTreeSet<usize>,
+        mut selector: F,
+    ) -> Vec<usize>
     where
         F: FnMut() -> f64,
     {
+        if self.scheduler.time_dependent() {
+            self.rebuild_trees();
+        }
         let tree: &mut OSTree = if pass {
             &mut self.tree_passed
         } else {
@@ -298,6 +729,14 @@ impl ProgressTable {
             val: i64,
         }
         let mut borrows = Vec::<TreeBorrow>::new();
+        for &idx in excluded.range(..self.entries.len()) {
+            borrows.push(TreeBorrow {
+                idx,
+                val: tree.value_at(idx),
+            });
+            tree.assign(idx, 0)
+        }
+        let skip = borrows.len();
         for _ in 0..n {
             let sum = tree.sum();
             if sum == 0 {
@@ -311,38 +750,124 @@ impl ProgressTable {
             });
             tree.assign(idx, 0)
         }
-        let result: Vec<usize> = borrows.iter().map(|x: &TreeBorrow| x.idx).collect();
+        let result: Vec<usize> = borrows[skip..].iter().map(|x: &TreeBorrow| x.idx).collect();
         for i in borrows {
             tree.assign(i.idx, i.val);
         }
         result
     }
 
-    pub fn set(&mut self, idx: usize, pass: bool) {
-        const SMOOTH_F: f64 = 0.5;
-        let us = self.unit_score().0 as f64;
+    fn apply<F>(&mut self, idx: usize, pass: bool, update: F)
+    where
+        F: FnOnce(&dyn Scheduler, &mut ProgressEntry, &SchedContext) -> i64,
+    {
+        let ctx = self.context();
         let entry = &mut self.entries[idx];
-        let dt0 = entry.distrust;
         if entry.pass {
             self.cnt_failed += 1;
         }
         if pass {
             self.cnt_failed -= 1;
         }
+        let w = update(self.scheduler.as_ref(), entry, &ctx);
         entry.pass = pass;
-        entry.distrust = if pass {
-            Score((dt0.0 + 1) / 2)
-        } else {
-            let a: f64 = ((dt0.0 as f64) / us).powf(SMOOTH_F);
-            Score((us * a) as i64)
-        };
-        self.tree_passed.assign(idx, if pass { dt0.0 } else { 0 });
-        self.tree_failed.assign(idx, if !pass { dt0.0 } else { 0 });
+        entry.first_seen.get_or_insert(ctx.now);
+        entry.last_reviewed = Some(ctx.now);
+        entry.reviews += 1;
+        self.tree_passed.assign(idx, if pass { w } else { 0 });
+        self.tree_failed.assign(idx, if !pass { w } else { 0 });
+    }
+
+    pub fn set(&mut self, idx: usize, pass: bool) {
+        self.apply(idx, pass, |s, entry, ctx| s.update(entry, pass, ctx))
+    }
+
+    /// Records an assessment graded by quality from 0 (blackout) to 5 (perfect).
+    /// Grades of 3 and above count as a pass.
+    pub fn set_quality(&mut self, idx: usize, quality: u8) {
+        let quality = quality.min(5);
+        self.apply(idx, quality >= 3, |s, entry, ctx| {
+            s.grade(entry, quality, ctx)
+        })
     }
 
     pub fn step(&mut self) {
         self.age += 1
     }
+
+    /// The current round of the Leitner system (see `sim_ex::LeitnerArgs`).
+    /// It is counted apart from the age, so that the rounds
+    /// do not affect the unit score or the intervals of the schedulers.
+    pub fn get_leitner_round(&self) -> u32 {
+        self.leitner_round
+    }
+
+    pub fn step_leitner_round(&mut self) {
+        self.leitner_round = self.leitner_round.wrapping_add(1)
+    }
+
+    /// Saves what `set` changes, so that it can be reverted by `restore_entry`.
+    pub fn save_entry(&self, idx: usize) -> EntrySnapshot {
+        EntrySnapshot {
+            idx,
+            entry: self.entries[idx],
+            cnt_failed: self.cnt_failed,
+            weight_passed: self.tree_passed.value_at(idx),
+            weight_failed: self.tree_failed.value_at(idx),
+            age: self.age,
+            leitner_round: self.leitner_round,
+        }
+    }
+
+    pub fn restore_entry(&mut self, snapshot: &EntrySnapshot) {
+        let idx = snapshot.idx;
+        self.entries[idx] = snapshot.entry;
+        self.cnt_failed = snapshot.cnt_failed;
+        self.tree_passed.assign(idx, snapshot.weight_passed);
+        self.tree_failed.assign(idx, snapshot.weight_failed);
+        self.age = snapshot.age;
+        self.leitner_round = snapshot.leitner_round;
+    }
+
+    /// Replaces an entry with a recorded state (see `journal::replay`).
+    /// The weight is derived as if the table was loaded from a file.
+    pub(crate) fn put_entry(&mut self, idx: usize, entry: ProgressEntry) {
+        if !self.entries[idx].pass {
+            self.cnt_failed -= 1;
+        }
+        if !entry.pass {
+            self.cnt_failed += 1;
+        }
+        self.entries[idx] = entry;
+        let w = self.scheduler.weight(&entry, &self.context());
+        self.tree_passed.assign(idx, if entry.pass { w } else { 0 });
+        self.tree_failed
+            .assign(idx, if !entry.pass { w } else { 0 });
+    }
+
+    pub(crate) fn set_age(&mut self, age: i32) {
+        self.age = age
+    }
+
+    pub(crate) fn set_leitner_round(&mut self, round: u32) {
+        self.leitner_round = round
+    }
+
+    pub(crate) fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+}
+
+/// State of a progress table entry, see `ProgressTable::save_entry`.
+#[derive(Clone, Copy, Debug)]
+pub struct EntrySnapshot {
+    pub idx: usize,
+    pub entry: ProgressEntry,
+    cnt_failed: usize,
+    weight_passed: i64,
+    weight_failed: i64,
+    age: i32,
+    leitner_round: u32,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -350,4 +875,191 @@ pub struct ProgressEntry {
     /// Variable size from 0 to UNIT
     pub distrust: Score,
     pub pass: bool,
+    /// State of the SM-2 algorithm, if it has been used on the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sm2: Option<Sm2State>,
+    /// State of the FSRS algorithm, if it has been used on the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsrs: Option<FsrsState>,
+    /// Time of the first assessment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<Timestamp>,
+    /// Time of the last assessment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reviewed: Option<Timestamp>,
+    /// Number of assessments.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reviews: u32,
+    /// Box of the Leitner system, counting from 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leitner_box: Option<u8>,
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+impl ProgressEntry {
+    /// A new entry, not yet passed.
+    pub fn new(distrust: Score) -> ProgressEntry {
+        ProgressEntry {
+            distrust,
+            pass: false,
+            sm2: None,
+            fsrs: None,
+            first_seen: None,
+            last_reviewed: None,
+            reviews: 0,
+            leitner_box: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(lhs: &str, rhs: &str, alternatives: &[&str]) -> TableEntry {
+        TableEntry {
+            lhs: String::from(lhs),
+            rhs: String::from(rhs),
+            alternatives: alternatives.iter().map(|x| String::from(*x)).collect(),
+            side: Side::Forward,
+            cloze: None,
+        }
+    }
+
+    #[test]
+    fn adding_an_alternative_keeps_progress() {
+        let before = vec![entry("auto", "car", &[]), entry("namas", "house", &[])];
+        let mut pt = ProgressTable::new(Arc::pin(before.clone()), LEGACY_SCORE_ARGS);
+        pt.set(0, true);
+        let path =
+            std::env::temp_dir().join(format!("ar-mintin-{}-progress.json", std::process::id()));
+        let view = serde_json::to_vec(&ProgressTableView::new(&pt, &before)).unwrap();
+        std::fs::write(&path, view).unwrap();
+        let after = vec![
+            entry("auto", "car", &["automobile"]),
+            entry("namas", "house", &[]),
+        ];
+        let loaded = ProgressTable::new_from_file(&after, &path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.ok().unwrap();
+        assert!(loaded.entries[0].pass);
+        assert_eq!(loaded.entries[0].distrust, pt.entries[0].distrust);
+        assert!(loaded.dormant.is_empty());
+        assert_eq!(loaded.get_unpassed_entries_count(), 1);
+    }
+
+    fn temp_file(name: &str, data: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ar-mintin-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// A progress file holding the records and `tail` after the `entries` array.
+    fn progress_file(records: &[String], tail: &str) -> String {
+        format!("{{\"entries\":[{}]{}", records.join(","), tail)
+    }
+
+    fn records(table: &[TableEntry]) -> Vec<String> {
+        table
+            .iter()
+            .map(|x| {
+                let mut pe = ProgressEntry::new(Score(5000));
+                pe.pass = true;
+                serde_json::to_string(&(pe, x)).unwrap()
+            })
+            .collect()
+    }
+
+    const TAIL: &str =
+        ",\"age\":5,\"score_args\":{\"degrade_factor\":0.8,\"origin\":10000,\"target\":100}}";
+
+    #[test]
+    fn lenient_load_drops_a_bad_record() {
+        let table = vec![
+            entry("a", "1", &[]),
+            entry("b", "2", &[]),
+            entry("c", "3", &[]),
+        ];
+        let mut records = records(&table);
+        records[1] = String::from("{\"bogus\":1}");
+        let path = temp_file("bad-record", &progress_file(&records, TAIL));
+        let strict = ProgressTable::new_from_file(&table, &path);
+        let lenient = ProgressTable::new_from_file_lenient(&table, &path, LEGACY_SCORE_ARGS);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(strict, Err(ProgressLoadError::UnknownSchema(_))));
+        let (pt, report) = lenient.ok().unwrap();
+        assert_eq!(report.dropped, [1]);
+        assert!(!report.truncated && !report.header_missing);
+        assert_eq!(pt.get_age(), 5);
+        assert!(pt.entries[0].pass && !pt.entries[1].pass && pt.entries[2].pass);
+    }
+
+    #[test]
+    fn lenient_load_keeps_the_records_before_a_truncation() {
+        let table = vec![
+            entry("a", "1", &[]),
+            entry("b", "2", &[]),
+            entry("c", "3", &[]),
+        ];
+        let records = records(&table);
+        let data = progress_file(&records, TAIL);
+        let cut = data.find(&records[2]).unwrap() + records[2].len() / 2;
+        let path = temp_file("truncated", &data[..cut]);
+        let lenient = ProgressTable::new_from_file_lenient(&table, &path, LEGACY_SCORE_ARGS);
+        std::fs::remove_file(&path).unwrap();
+        let (pt, report) = lenient.ok().unwrap();
+        assert!(report.dropped.is_empty());
+        assert!(report.truncated && report.header_missing);
+        assert_eq!(pt.get_age(), 0);
+        assert!(pt.entries[0].pass && pt.entries[1].pass && !pt.entries[2].pass);
+    }
+
+    #[test]
+    fn strict_load_errors() {
+        let table = vec![entry("a", "1", &[])];
+        let load = |name: &str, data: &str| {
+            let path = temp_file(name, data);
+            let result = ProgressTable::new_from_file(&table, &path);
+            std::fs::remove_file(&path).unwrap();
+            result
+        };
+        assert!(matches!(
+            load("not-json", "{\"entries\":["),
+            Err(ProgressLoadError::NotJson(_))
+        ));
+        assert!(matches!(
+            load("unknown", "{\"entries\":[],\"version\":2}"),
+            Err(ProgressLoadError::UnknownSchema(_))
+        ));
+        assert!(matches!(
+            load("legacy-stp", "{\"entries\":[],\"stp\":1.0}"),
+            Err(ProgressLoadError::LegacyStpOutOfRange(x)) if x == 1.0
+        ));
+    }
+
+    #[test]
+    fn distrust_scheduler_keeps_the_baseline_numbers() {
+        let table = vec![entry("a", "1", &[]), entry("b", "2", &[])];
+        let mut pt = ProgressTable::new(Arc::pin(table), LEGACY_SCORE_ARGS);
+        let us = pt.unit_score().0;
+        let d0 = pt.entries[0].distrust.0;
+        assert_eq!(d0, us);
+        pt.set(0, true);
+        let d1 = pt.entries[0].distrust.0;
+        assert_eq!(d1, (d0 + 1) / 2);
+        assert_eq!(pt.tree_passed.value_at(0), d0);
+        assert_eq!(pt.tree_failed.value_at(0), 0);
+        pt.set(0, false);
+        let d2 = pt.entries[0].distrust.0;
+        assert_eq!(d2, (us as f64 * (d1 as f64 / us as f64).powf(0.5)) as i64);
+        assert_eq!(pt.tree_failed.value_at(0), d1);
+        assert_eq!(pt.tree_passed.value_at(0), 0);
+        pt.set(0, true);
+        assert_eq!(pt.entries[0].distrust.0, (d2 + 1) / 2);
+        assert_eq!(pt.tree_passed.value_at(0), d2);
+    }
 }
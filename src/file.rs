@@ -25,7 +25,7 @@ use crate::ent::TableEntry;
 
 pub fn load_table(path: &Path) -> Vec<TableEntry> {
     let input: json::JsonValue = {
-        let mut file = File::open(&path).unwrap();
+        let mut file = File::open(path).unwrap();
         let mut file_data = String::new();
         file.read_to_string(&mut file_data).unwrap();
         json::parse(&file_data).unwrap()
@@ -35,8 +35,8 @@ pub fn load_table(path: &Path) -> Vec<TableEntry> {
     let table: Vec<TableEntry> = data
         .members()
         .map(|x| TableEntry {
-            lhs: String::from((&x[0]).as_str().unwrap()),
-            rhs: String::from((&x[1]).as_str().unwrap()),
+            lhs: String::from(x[0].as_str().unwrap()),
+            rhs: String::from(x[1].as_str().unwrap()),
         })
         .collect();
     table
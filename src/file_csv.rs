@@ -0,0 +1,311 @@
+/*
+ * file_csv.rs -- Reading and writing tables in CSV/TSV format
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::ent_ex::{Side, TableEntry};
+use crate::file_ex::{write_atomic, DeckLoadError};
+
+/// Column labels which make the first record count as a header
+/// when `HeaderMode::Auto` is used (see `is_header`).
+const HEADER_LABELS: &[&str] = &[
+    "lhs",
+    "rhs",
+    "front",
+    "back",
+    "question",
+    "answer",
+    "term",
+    "definition",
+    "word",
+    "translation",
+    "source",
+    "target",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderMode {
+    /// The first record is a header if all the selected columns have known labels
+    /// (e.g. `front`, `back`, `term`, `definition`).
+    Auto,
+    Present,
+    Absent,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// Zero-based column index.
+    Index(usize),
+    /// Column name, as written in the header record.
+    Name(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub header: HeaderMode,
+    pub lhs: Column,
+    pub rhs: Column,
+    /// Separates the accepted answers within the `rhs` cell (`|` by default).
+    /// Empty alternative answers are ignored (e.g. in `car|`).
+    /// If not set, the cell holds a single answer.
+    pub alternatives: Option<char>,
+}
+
+impl CsvOptions {
+    pub fn csv() -> CsvOptions {
+        CsvOptions {
+            delimiter: b',',
+            header: HeaderMode::Auto,
+            lhs: Column::Index(0),
+            rhs: Column::Index(1),
+            alternatives: Some('|'),
+        }
+    }
+
+    pub fn tsv() -> CsvOptions {
+        CsvOptions {
+            delimiter: b'\t',
+            ..Self::csv()
+        }
+    }
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self::csv()
+    }
+}
+
+/// Whether every selected column of the record holds a known label,
+/// so that a data record which merely contains a label (e.g. `žodis,word`) is kept.
+fn is_header(record: &csv::StringRecord, opts: &CsvOptions) -> bool {
+    [&opts.lhs, &opts.rhs]
+        .into_iter()
+        .all(|column| match column {
+            Column::Index(i) => record
+                .get(*i)
+                .is_some_and(|x| HEADER_LABELS.contains(&x.trim().to_lowercase().as_str())),
+            Column::Name(_) => true,
+        })
+}
+
+fn resolve(column: &Column, header: Option<&csv::StringRecord>) -> Result<usize, DeckLoadError> {
+    match column {
+        Column::Index(i) => Ok(*i),
+        Column::Name(name) => header
+            .and_then(|h| h.iter().position(|x| x.trim() == name))
+            .ok_or_else(|| DeckLoadError::UnknownColumn(name.clone())),
+    }
+}
+
+/// Reads a table from CSV/TSV data.
+/// Quoting follows RFC 4180.
+/// Fails with `EmptyAnswer` if the first answer of the `rhs` cell is empty.
+/// Rows in the errors are zero-based record numbers, the header included.
+pub fn read_table<R: Read>(reader: R, opts: &CsvOptions) -> Result<Vec<TableEntry>, DeckLoadError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(opts.delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut records = rdr.records().enumerate().peekable();
+    let header = match records.peek() {
+        Some((_, Ok(first))) => {
+            let named = matches!(opts.lhs, Column::Name(_)) || matches!(opts.rhs, Column::Name(_));
+            let present = match opts.header {
+                HeaderMode::Present => true,
+                HeaderMode::Absent => false,
+                HeaderMode::Auto => named || is_header(first, opts),
+            };
+            if present {
+                Some(first.clone())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+    if header.is_some() {
+        records.next();
+    }
+    let lhs = resolve(&opts.lhs, header.as_ref())?;
+    let rhs = resolve(&opts.rhs, header.as_ref())?;
+    records
+        .map(|(row, record)| {
+            let record = record?;
+            let cell = |column: usize| {
+                record
+                    .get(column)
+                    .map(String::from)
+                    .ok_or(DeckLoadError::MissingColumn { row, column })
+            };
+            let rhs = cell(rhs)?;
+            let (rhs, alternatives) = match opts.alternatives {
+                Some(sep) => {
+                    let mut answers = rhs.split(sep).map(str::trim);
+                    let first = String::from(answers.next().unwrap_or_default());
+                    let rest = answers.filter(|x| !x.is_empty()).map(String::from);
+                    (first, rest.collect())
+                }
+                None => (rhs, Vec::new()),
+            };
+            if rhs.trim().is_empty() {
+                return Err(DeckLoadError::EmptyAnswer { row });
+            }
+            Ok(TableEntry {
+                lhs: cell(lhs)?,
+                rhs,
+                alternatives,
+                side: Side::Forward,
+                cloze: None,
+            })
+        })
+        .collect()
+}
+
+/// Writes a table as CSV/TSV data.
+/// A header record is written only if `opts.header` is `Present`;
+/// its labels are taken from the column names, or `lhs`/`rhs` otherwise.
+/// Fails with `InvalidInput` rather than losing answers:
+/// if an entry has alternative answers but `opts.alternatives` is not set,
+/// or if an answer contains the separator.
+pub fn write_table<W: Write>(
+    writer: W,
+    table: &[TableEntry],
+    opts: &CsvOptions,
+) -> std::io::Result<()> {
+    let lossy = |row: usize, reason: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("row {}: {}", row, reason),
+        )
+    };
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(opts.delimiter)
+        .from_writer(writer);
+    if opts.header == HeaderMode::Present {
+        let label = |column: &Column, default: &'static str| match column {
+            Column::Name(name) => name.clone(),
+            Column::Index(_) => String::from(default),
+        };
+        wtr.write_record([label(&opts.lhs, "lhs"), label(&opts.rhs, "rhs")])?;
+    }
+    for (row, entry) in table.iter().enumerate() {
+        let rhs = match opts.alternatives {
+            Some(sep) => {
+                if entry.answers().any(|x| x.contains(sep)) {
+                    return Err(lossy(row, "an answer contains the separator"));
+                }
+                let answers: Vec<&str> = entry.answers().map(String::as_str).collect();
+                answers.join(sep.encode_utf8(&mut [0; 4]))
+            }
+            None if !entry.alternatives.is_empty() => {
+                return Err(lossy(row, "alternative answers need a separator"));
+            }
+            None => entry.rhs.clone(),
+        };
+        wtr.write_record([&entry.lhs, &rhs])?;
+    }
+    wtr.flush()
+}
+
+pub fn load_table(path: &Path, opts: &CsvOptions) -> Result<Vec<TableEntry>, DeckLoadError> {
+    read_table(File::open(path)?, opts)
+}
+
+pub fn save_table(path: &Path, table: &[TableEntry], opts: &CsvOptions) -> std::io::Result<()> {
+    write_atomic(path, |file| write_table(file, table, opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grade::Verdict;
+
+    fn read(data: &str) -> Vec<TableEntry> {
+        read_table(data.as_bytes(), &CsvOptions::csv())
+            .ok()
+            .unwrap()
+    }
+
+    #[test]
+    fn header_detected_when_all_columns_are_labels() {
+        let table = read("Term,Definition\nnamas,house\n");
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].lhs, "namas");
+    }
+
+    fn entry(lhs: &str, rhs: &str, alternatives: &[&str]) -> TableEntry {
+        TableEntry {
+            lhs: String::from(lhs),
+            rhs: String::from(rhs),
+            alternatives: alternatives.iter().map(|x| String::from(*x)).collect(),
+            side: Side::Forward,
+            cloze: None,
+        }
+    }
+
+    #[test]
+    fn alternatives_round_trip() {
+        let table = vec![entry("auto", "car", &["automobile"])];
+        let mut out = Vec::new();
+        write_table(&mut out, &table, &CsvOptions::csv()).unwrap();
+        let back = read_table(out.as_slice(), &CsvOptions::csv()).ok().unwrap();
+        assert_eq!(back, table);
+    }
+
+    #[test]
+    fn empty_answers_are_dropped() {
+        let table = read("auto,car|\nnamas,house||home\n");
+        assert!(table[0].alternatives.is_empty());
+        assert_eq!(table[1].alternatives, ["home"]);
+        assert_eq!(table[0].assess(String::new()), Verdict::Wrong);
+        for data in ["auto,|car\n", "auto,\n", "auto, | \n"] {
+            let result = read_table(data.as_bytes(), &CsvOptions::csv());
+            assert!(
+                matches!(result, Err(DeckLoadError::EmptyAnswer { row: 0 })),
+                "{}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn lossy_output_is_refused() {
+        let opts = CsvOptions {
+            alternatives: None,
+            ..CsvOptions::csv()
+        };
+        let table = [entry("auto", "car", &["automobile"])];
+        assert!(write_table(Vec::new(), &table, &opts).is_err());
+        let table = [entry("or", "either|or", &[])];
+        assert!(write_table(Vec::new(), &table, &CsvOptions::csv()).is_err());
+    }
+
+    #[test]
+    fn record_with_a_single_label_is_data() {
+        let table = read("žodis,word\nnamas,house\n");
+        assert_eq!(table.len(), 2);
+        assert_eq!(table[0].lhs, "žodis");
+        assert_eq!(table[0].rhs, "word");
+    }
+}
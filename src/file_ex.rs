@@ -17,27 +17,488 @@
  *
  */
 
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
 
-use crate::ent_ex::TableEntry;
+use crate::ent_ex::{Deck, Score, ScoreArgs, Side, TableEntry};
+use crate::file_csv;
+use crate::file_csv::CsvOptions;
+use crate::norm::AnswerNormalizer;
 
-pub fn load_table(path: &Path) -> Vec<TableEntry> {
+/// Reasons why a deck file could not be loaded.
+/// Row and column positions are zero-based indices into the `data` array
+/// (or zero-based record numbers for CSV/TSV tables).
+#[derive(Debug)]
+pub enum DeckLoadError {
+    Io(std::io::Error),
+    Parse(json::Error),
+    /// The `version` field is missing or holds an unsupported value.
+    UnsupportedVersion(String),
+    /// The `data` field is missing or is not an array.
+    MissingData,
+    /// The row is not an array.
+    NotARow {
+        row: usize,
+    },
+    /// The row does not hold exactly two columns.
+    Arity {
+        row: usize,
+        found: usize,
+    },
+    /// The cell is not a string
+    /// (or, in the answer column, neither a string nor a non-empty list of strings).
+    NotAString {
+        row: usize,
+        column: usize,
+    },
+    Csv(csv::Error),
+    /// The record is too short to contain the selected column.
+    MissingColumn {
+        row: usize,
+        column: usize,
+    },
+    /// The answer cell holds no answer.
+    EmptyAnswer {
+        row: usize,
+    },
+    /// No header column has the given name.
+    UnknownColumn(String),
+    /// The metadata field has a wrong type.
+    BadField(&'static str),
+}
+
+impl fmt::Display for DeckLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeckLoadError::Io(e) => write!(f, "cannot read deck: {}", e),
+            DeckLoadError::Parse(e) => write!(f, "deck is not valid JSON: {}", e),
+            DeckLoadError::UnsupportedVersion(v) => write!(f, "unsupported deck version: {}", v),
+            DeckLoadError::MissingData => write!(f, "deck has no `data` array"),
+            DeckLoadError::NotARow { row } => write!(f, "row {}: expected an array", row),
+            DeckLoadError::Arity { row, found } => {
+                write!(f, "row {}: expected 2 columns, found {}", row, found)
+            }
+            DeckLoadError::NotAString { row, column } => {
+                write!(f, "row {}, column {}: expected a string", row, column)
+            }
+            DeckLoadError::Csv(e) => write!(f, "deck is not valid CSV: {}", e),
+            DeckLoadError::MissingColumn { row, column } => {
+                write!(f, "row {}: column {} is missing", row, column)
+            }
+            DeckLoadError::EmptyAnswer { row } => write!(f, "row {}: the answer is empty", row),
+            DeckLoadError::UnknownColumn(name) => write!(f, "no column named `{}`", name),
+            DeckLoadError::BadField(field) => write!(f, "deck field `{}` is malformed", field),
+        }
+    }
+}
+
+impl std::error::Error for DeckLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeckLoadError::Io(e) => Some(e),
+            DeckLoadError::Parse(e) => Some(e),
+            DeckLoadError::Csv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DeckLoadError {
+    fn from(e: std::io::Error) -> Self {
+        DeckLoadError::Io(e)
+    }
+}
+
+impl From<json::Error> for DeckLoadError {
+    fn from(e: json::Error) -> Self {
+        DeckLoadError::Parse(e)
+    }
+}
+
+impl From<csv::Error> for DeckLoadError {
+    fn from(e: csv::Error) -> Self {
+        DeckLoadError::Csv(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeckFormat {
+    Json,
+    Csv,
+    Tsv,
+}
+
+impl DeckFormat {
+    /// Guesses the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<DeckFormat> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "json" => Some(DeckFormat::Json),
+            "csv" => Some(DeckFormat::Csv),
+            "tsv" | "tab" => Some(DeckFormat::Tsv),
+            _ => None,
+        }
+    }
+}
+
+fn parse_row(row: usize, x: &json::JsonValue) -> Result<TableEntry, DeckLoadError> {
+    if !x.is_array() {
+        return Err(DeckLoadError::NotARow { row });
+    }
+    if x.len() != 2 {
+        return Err(DeckLoadError::Arity {
+            row,
+            found: x.len(),
+        });
+    }
+    let cell = |x: &json::JsonValue, column: usize| {
+        x.as_str()
+            .map(String::from)
+            .ok_or(DeckLoadError::NotAString { row, column })
+    };
+    let mut answers = if x[1].is_array() {
+        x[1].members()
+            .map(|y| cell(y, 1))
+            .collect::<Result<Vec<String>, DeckLoadError>>()?
+    } else {
+        vec![cell(&x[1], 1)?]
+    };
+    if answers.is_empty() {
+        return Err(DeckLoadError::NotAString { row, column: 1 });
+    }
+    Ok(TableEntry {
+        lhs: cell(&x[0], 0)?,
+        rhs: answers.remove(0),
+        alternatives: answers,
+        side: Side::Forward,
+        cloze: None,
+    })
+}
+
+fn parse_data(input: &json::JsonValue) -> Result<Vec<TableEntry>, DeckLoadError> {
+    let data = &input["data"];
+    if !data.is_array() {
+        return Err(DeckLoadError::MissingData);
+    }
+    data.members()
+        .enumerate()
+        .map(|(row, x)| parse_row(row, x))
+        .collect()
+}
+
+fn parse_text(
+    input: &json::JsonValue,
+    field: &'static str,
+) -> Result<Option<String>, DeckLoadError> {
+    let x = &input[field];
+    if x.is_null() {
+        Ok(None)
+    } else {
+        x.as_str()
+            .map(|s| Some(String::from(s)))
+            .ok_or(DeckLoadError::BadField(field))
+    }
+}
+
+fn parse_score_args(input: &json::JsonValue) -> Result<Option<ScoreArgs>, DeckLoadError> {
+    const FIELD: &str = "score_args";
+    let x = &input[FIELD];
+    if x.is_null() {
+        return Ok(None);
+    }
+    let score = |key: &str| {
+        x[key]
+            .as_i64()
+            .map(Score)
+            .ok_or(DeckLoadError::BadField(FIELD))
+    };
+    Ok(Some(ScoreArgs {
+        degrade_factor: x["degrade_factor"]
+            .as_f64()
+            .ok_or(DeckLoadError::BadField(FIELD))?,
+        origin: score("origin")?,
+        target: score("target")?,
+    }))
+}
+
+fn parse_normalizer(input: &json::JsonValue) -> Result<Option<AnswerNormalizer>, DeckLoadError> {
+    const FIELD: &str = "normalizer";
+    let x = &input[FIELD];
+    if x.is_null() {
+        return Ok(None);
+    }
+    serde_json::from_str(&x.dump())
+        .map(Some)
+        .map_err(|_| DeckLoadError::BadField(FIELD))
+}
+
+/// Loads a deck in either the version 1 or the version 2 format.
+/// Version 1 decks have no metadata.
+pub fn load_deck(path: &Path) -> Result<Deck, DeckLoadError> {
     let input: json::JsonValue = {
-        let mut file = File::open(&path).unwrap();
+        let mut file = File::open(path)?;
         let mut file_data = String::new();
-        file.read_to_string(&mut file_data).unwrap();
-        json::parse(&file_data).unwrap()
+        file.read_to_string(&mut file_data)?;
+        json::parse(&file_data)?
     };
-    assert!(input["version"] == 1i32);
-    let data = &input["data"];
-    let table: Vec<TableEntry> = data
-        .members()
-        .map(|x| TableEntry {
-            lhs: String::from((&x[0]).as_str().unwrap()),
-            rhs: String::from((&x[1]).as_str().unwrap()),
-        })
-        .collect();
-    table
+    match input["version"].as_i32() {
+        Some(1) => Ok(Deck {
+            entries: parse_data(&input)?,
+            ..Deck::default()
+        }),
+        Some(2) => Ok(Deck {
+            title: parse_text(&input, "title")?,
+            description: parse_text(&input, "description")?,
+            source_language: parse_text(&input, "source_language")?,
+            target_language: parse_text(&input, "target_language")?,
+            author: parse_text(&input, "author")?,
+            license: parse_text(&input, "license")?,
+            score_args: parse_score_args(&input)?,
+            normalizer: parse_normalizer(&input)?,
+            entries: parse_data(&input)?,
+        }),
+        _ => Err(DeckLoadError::UnsupportedVersion(input["version"].dump())),
+    }
+}
+
+pub fn load_table(path: &Path) -> Result<Vec<TableEntry>, DeckLoadError> {
+    load_deck(path).map(|deck| deck.entries)
+}
+
+fn write_data<W: Write>(writer: &mut W, table: &[TableEntry]) -> std::io::Result<()> {
+    writeln!(writer, "    \"data\": [ ")?;
+    for (i, entry) in table.iter().enumerate() {
+        let sep = if i + 1 < table.len() { "," } else { "" };
+        let rhs = if entry.alternatives.is_empty() {
+            json::stringify(entry.rhs.as_str())
+        } else {
+            let answers: Vec<String> = entry
+                .answers()
+                .map(|x| json::stringify(x.as_str()))
+                .collect();
+            format!("[ {} ]", answers.join(", "))
+        };
+        writeln!(
+            writer,
+            "    [ {}, {} ]{}",
+            json::stringify(entry.lhs.as_str()),
+            rhs,
+            sep
+        )?;
+    }
+    write!(writer, "    ]\n}}")
+}
+
+/// Writes a table in the JSON format read by `load_table`.
+/// The output follows the layout of `demo.json`.
+pub fn write_table<W: Write>(mut writer: W, table: &[TableEntry]) -> std::io::Result<()> {
+    write!(writer, "{{\n    \"version\": 1,\n")?;
+    write_data(&mut writer, table)
+}
+
+/// Writes a deck in the version 2 format.
+/// Absent metadata fields are omitted.
+pub fn write_deck<W: Write>(mut writer: W, deck: &Deck) -> std::io::Result<()> {
+    write!(writer, "{{\n    \"version\": 2,\n")?;
+    let fields = [
+        ("title", &deck.title),
+        ("description", &deck.description),
+        ("source_language", &deck.source_language),
+        ("target_language", &deck.target_language),
+        ("author", &deck.author),
+        ("license", &deck.license),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            writeln!(
+                writer,
+                "    \"{}\": {},",
+                key,
+                json::stringify(value.as_str())
+            )?;
+        }
+    }
+    if let Some(sa) = &deck.score_args {
+        writeln!(
+            writer,
+            "    \"score_args\": {{ \"degrade_factor\": {}, \"origin\": {}, \"target\": {} }},",
+            json::stringify(sa.degrade_factor),
+            sa.origin.0,
+            sa.target.0
+        )?;
+    }
+    if let Some(norm) = &deck.normalizer {
+        let norm = serde_json::to_string(norm).map_err(std::io::Error::from)?;
+        writeln!(writer, "    \"normalizer\": {},", norm)?;
+    }
+    write_data(&mut writer, &deck.entries)
+}
+
+/// Writes a file by writing to a temporary file in the same directory first
+/// and renaming it over `path`, so that the file is never left half-written.
+pub(crate) fn write_atomic<F>(path: &Path, contents: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut File) -> std::io::Result<()>,
+{
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let result = File::create(&tmp).and_then(|mut file| {
+        contents(&mut file)?;
+        file.sync_all()
+    });
+    match result.and_then(|_| std::fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+pub fn save_table(path: &Path, table: &[TableEntry]) -> std::io::Result<()> {
+    write_atomic(path, |file| {
+        let mut writer = std::io::BufWriter::new(file);
+        write_table(&mut writer, table)?;
+        writer.flush()
+    })
+}
+
+pub fn save_deck(path: &Path, deck: &Deck) -> std::io::Result<()> {
+    write_atomic(path, |file| {
+        let mut writer = std::io::BufWriter::new(file);
+        write_deck(&mut writer, deck)?;
+        writer.flush()
+    })
+}
+
+/// Loads a deck in the format given by the file extension.
+/// Files with an unknown extension are read as JSON.
+/// CSV/TSV decks have no metadata.
+pub fn load_deck_auto(path: &Path) -> Result<Deck, DeckLoadError> {
+    let entries = match DeckFormat::from_path(path).unwrap_or(DeckFormat::Json) {
+        DeckFormat::Json => return load_deck(path),
+        DeckFormat::Csv => file_csv::load_table(path, &CsvOptions::csv())?,
+        DeckFormat::Tsv => file_csv::load_table(path, &CsvOptions::tsv())?,
+    };
+    Ok(Deck {
+        entries,
+        ..Deck::default()
+    })
+}
+
+/// Loads a table in the format given by the file extension.
+/// Files with an unknown extension are read as JSON.
+pub fn load_table_auto(path: &Path) -> Result<Vec<TableEntry>, DeckLoadError> {
+    load_deck_auto(path).map(|deck| deck.entries)
+}
+
+/// Saves a table in the format given by the file extension.
+/// Files with an unknown extension are written as JSON.
+pub fn save_table_auto(path: &Path, table: &[TableEntry]) -> std::io::Result<()> {
+    match DeckFormat::from_path(path).unwrap_or(DeckFormat::Json) {
+        DeckFormat::Json => save_table(path, table),
+        DeckFormat::Csv => file_csv::save_table(path, table, &CsvOptions::csv()),
+        DeckFormat::Tsv => file_csv::save_table(path, table, &CsvOptions::tsv()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Loads the file and writes it back with `write_table`.
+    fn round_trip(path: &Path) -> Vec<u8> {
+        let table = load_table(path).ok().unwrap();
+        let mut out = Vec::new();
+        write_table(&mut out, &table).unwrap();
+        out
+    }
+
+    #[test]
+    fn demo_round_trips() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("demo.json");
+        assert_eq!(round_trip(&path), std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn escapes_and_alternatives_round_trip() {
+        let canonical = concat!(
+            "{\n",
+            "    \"version\": 1,\n",
+            "    \"data\": [ \n",
+            "    [ \"say \\\"hi\\\"\", \"back\\\\slash\\ttab\" ],\n",
+            "    [ \"auto\", [ \"car\", \"automobile\" ] ]\n",
+            "    ]\n",
+            "}"
+        );
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("ar-mintin-{}-deck.json", std::process::id()));
+        std::fs::write(&path, canonical).unwrap();
+        let table = load_table(&path).ok().unwrap();
+        let out = round_trip(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(table[0].lhs, "say \"hi\"");
+        assert_eq!(table[0].rhs, "back\\slash\ttab");
+        assert_eq!(table[1].alternatives, ["automobile"]);
+        assert_eq!(String::from_utf8(out).unwrap(), canonical);
+    }
+
+    fn load(name: &str, data: &str) -> Result<Deck, DeckLoadError> {
+        let path =
+            std::env::temp_dir().join(format!("ar-mintin-{}-{}.json", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        let result = load_deck(&path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn errors_point_at_the_row_and_column() {
+        let rows = |rows: &str| format!("{{\"version\": 1, \"data\": [[\"a\", \"b\"], {}]}}", rows);
+        assert!(matches!(
+            load("arity", &rows("[\"a\", \"b\", \"c\"]")),
+            Err(DeckLoadError::Arity { row: 1, found: 3 })
+        ));
+        assert!(matches!(
+            load("lhs", &rows("[1, \"b\"]")),
+            Err(DeckLoadError::NotAString { row: 1, column: 0 })
+        ));
+        assert!(matches!(
+            load("rhs", &rows("[\"a\", [\"b\", 2]]")),
+            Err(DeckLoadError::NotAString { row: 1, column: 1 })
+        ));
+        assert!(matches!(
+            load("no-answers", &rows("[\"a\", []]")),
+            Err(DeckLoadError::NotAString { row: 1, column: 1 })
+        ));
+        assert!(matches!(
+            load("not-a-row", &rows("\"a\"")),
+            Err(DeckLoadError::NotARow { row: 1 })
+        ));
+    }
+
+    #[test]
+    fn errors_in_the_deck_structure() {
+        assert!(matches!(
+            load("no-data", "{\"version\": 1}"),
+            Err(DeckLoadError::MissingData)
+        ));
+        assert!(matches!(
+            load("data-object", "{\"version\": 2, \"data\": {}}"),
+            Err(DeckLoadError::MissingData)
+        ));
+        match load("version", "{\"version\": 3, \"data\": []}") {
+            Err(DeckLoadError::UnsupportedVersion(v)) => assert_eq!(v, "3"),
+            _ => panic!("version 3 accepted"),
+        }
+        match load("no-version", "{\"data\": []}") {
+            Err(DeckLoadError::UnsupportedVersion(v)) => assert_eq!(v, "null"),
+            _ => panic!("missing version accepted"),
+        }
+    }
 }
@@ -0,0 +1,180 @@
+/*
+ * grade.rs -- Grading of the typed answers
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use serde::{Deserialize, Serialize};
+
+/// Result of comparing the user input with the accepted answers.
+/// `answer` is the position of the matched answer within `TableEntry::answers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verdict {
+    Exact { answer: usize },
+    AcceptedWithTypo { answer: usize, distance: usize },
+    Wrong,
+}
+
+impl Verdict {
+    pub fn answer(&self) -> Option<usize> {
+        match *self {
+            Verdict::Exact { answer } | Verdict::AcceptedWithTypo { answer, .. } => Some(answer),
+            Verdict::Wrong => None,
+        }
+    }
+
+    /// Decides whether the verdict counts as a pass.
+    pub fn pass(&self, policy: TypoPolicy) -> bool {
+        match self {
+            Verdict::Exact { .. } => true,
+            Verdict::AcceptedWithTypo { .. } => policy == TypoPolicy::Pass,
+            Verdict::Wrong => false,
+        }
+    }
+}
+
+/// Self-assessment of a revealed answer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Grade {
+    Again,
+    Hard,
+    Good,
+    Easy,
+}
+
+impl Grade {
+    /// The grade on the scale of `ProgressTable::set_quality`.
+    pub fn quality(self) -> u8 {
+        match self {
+            Grade::Again => 1,
+            Grade::Hard => 3,
+            Grade::Good => 4,
+            Grade::Easy => 5,
+        }
+    }
+
+    pub fn pass(self) -> bool {
+        self != Grade::Again
+    }
+}
+
+/// Whether an answer accepted with a typo counts as a pass.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypoPolicy {
+    #[default]
+    Pass,
+    Fail,
+}
+
+/// The number of tolerated typos is `ratio` times the answer length
+/// (in characters), rounded down, but no more than `max`.
+/// The default tolerance accepts no typos.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TypoTolerance {
+    pub ratio: f64,
+    pub max: usize,
+}
+
+impl TypoTolerance {
+    /// One typo per five characters, at most three.
+    pub fn lenient() -> TypoTolerance {
+        TypoTolerance { ratio: 0.2, max: 3 }
+    }
+
+    pub fn allowed(&self, answer: &str) -> usize {
+        let n = answer.chars().count() as f64;
+        ((n * self.ratio).floor() as usize).min(self.max)
+    }
+}
+
+/// Damerau-Levenshtein distance (optimal string alignment variant):
+/// the number of insertions, deletions, substitutions
+/// and transpositions of adjacent characters.
+pub fn damerau_levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let (n, m) = (a.len(), b.len());
+    let mut d = vec![vec![0usize; m + 1]; n + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=m).collect();
+    for i in 1..=n {
+        for j in 1..=m {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[n][m]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_counts_edits() {
+        assert_eq!(damerau_levenshtein("", ""), 0);
+        assert_eq!(damerau_levenshtein("", "abc"), 3);
+        assert_eq!(damerau_levenshtein("kitten", "sitting"), 3);
+        assert_eq!(damerau_levenshtein("namas", "namas"), 0);
+    }
+
+    #[test]
+    fn transposition_is_one_edit() {
+        assert_eq!(damerau_levenshtein("ab", "ba"), 1);
+        assert_eq!(damerau_levenshtein("hosue", "house"), 1);
+    }
+
+    #[test]
+    fn transposed_characters_are_not_edited_again() {
+        // Optimal string alignment: the unrestricted distance would be 2
+        assert_eq!(damerau_levenshtein("ca", "abc"), 3);
+    }
+
+    #[test]
+    fn distance_counts_characters_not_bytes() {
+        assert_eq!(damerau_levenshtein("žodis", "zodis"), 1);
+        assert_eq!(damerau_levenshtein("ąč", "čą"), 1);
+    }
+
+    #[test]
+    fn tolerance_is_capped() {
+        let tolerance = TypoTolerance::lenient();
+        assert_eq!(tolerance.allowed("dog"), 0);
+        assert_eq!(tolerance.allowed("house"), 1);
+        assert_eq!(tolerance.allowed("automobile"), 2);
+        assert_eq!(tolerance.allowed("a very long answer indeed"), 3);
+        assert_eq!(TypoTolerance::default().allowed("automobile"), 0);
+    }
+
+    #[test]
+    fn typo_policy_decides_the_pass() {
+        let typo = Verdict::AcceptedWithTypo {
+            answer: 0,
+            distance: 1,
+        };
+        assert!(typo.pass(TypoPolicy::Pass));
+        assert!(!typo.pass(TypoPolicy::Fail));
+        assert!(Verdict::Exact { answer: 1 }.pass(TypoPolicy::Fail));
+        assert!(!Verdict::Wrong.pass(TypoPolicy::Pass));
+    }
+}
@@ -0,0 +1,317 @@
+/*
+ * journal.rs -- Session event log
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::clock::Timestamp;
+use crate::ent_ex::{Idx, ProgressEntry, ProgressLoadError, ProgressTable, TableEntry};
+use crate::sim_ex::{Action, Change, UiMessage};
+use serde::{Deserialize, Serialize};
+
+/// A call to the simulation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum LogEvent {
+    /// The log was attached to the simulation (see `Simulation::set_log`).
+    Start { seed: Option<u64> },
+    /// `Simulation::next`.
+    Next {
+        action: Action,
+        message: UiMessage,
+        change: Option<Change>,
+    },
+    /// `Simulation::override_last`.
+    Override {
+        pass: bool,
+        message: UiMessage,
+        change: Change,
+    },
+    /// `Simulation::undo`.
+    Undo { message: Option<UiMessage> },
+}
+
+/// A line of the session log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub time: Timestamp,
+    #[serde(flatten)]
+    pub event: LogEvent,
+    /// The age of the progress table after the event.
+    pub age: i32,
+    /// The Leitner round of the progress table after the event.
+    #[serde(default)]
+    pub leitner_round: u32,
+    /// The progress entry changed by the event, in its new state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<(Idx, ProgressEntry)>,
+}
+
+/// Writes the records as JSON Lines, flushing after every record.
+/// Logging stops at the first error, see `error`.
+pub struct SessionLog {
+    out: Box<dyn Write + Send>,
+    error: Option<std::io::Error>,
+}
+
+impl SessionLog {
+    pub fn new(out: Box<dyn Write + Send>) -> SessionLog {
+        SessionLog { out, error: None }
+    }
+
+    /// Opens the log file for appending, creating it if needed.
+    pub fn open(path: &Path) -> std::io::Result<SessionLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn append(&mut self, record: &LogRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_vec(record)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.out.write_all(&line)?;
+                self.out.flush()
+            });
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    /// The error which stopped the logging, if any.
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+}
+
+/// Reasons why a session could not be replayed.
+/// Line and record positions are zero-based.
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Progress(ProgressLoadError),
+    /// The line is not a log record.
+    Parse {
+        line: usize,
+        error: serde_json::Error,
+    },
+    /// The record changes an entry outside of the progress table.
+    OutOfRange {
+        record: usize,
+        idx: Idx,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "cannot read session log: {}", e),
+            ReplayError::Progress(e) => write!(f, "cannot load progress: {}", e),
+            ReplayError::Parse { line, error } => write!(f, "line {}: {}", line, error),
+            ReplayError::OutOfRange { record, idx } => {
+                write!(f, "record {}: entry {} is out of range", record, idx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::Io(e) => Some(e),
+            ReplayError::Progress(e) => Some(e),
+            ReplayError::Parse { error, .. } => Some(error),
+            ReplayError::OutOfRange { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(e: std::io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+impl From<ProgressLoadError> for ReplayError {
+    fn from(e: ProgressLoadError) -> Self {
+        ReplayError::Progress(e)
+    }
+}
+
+/// Reads the records of a session log.
+/// An unterminated last line (e.g. cut short by a crash) is ignored
+/// unless it is a complete record.
+pub fn read_log<R: Read>(mut reader: R) -> Result<Vec<LogRecord>, ReplayError> {
+    let mut buf = String::new();
+    reader.read_to_string(&mut buf)?;
+    let mut records = Vec::new();
+    for (line, text) in buf.split_inclusive('\n').enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(text) {
+            Ok(record) => records.push(record),
+            Err(_) if !text.ends_with('\n') => break,
+            Err(error) => return Err(ReplayError::Parse { line, error }),
+        }
+    }
+    Ok(records)
+}
+
+/// Applies the records to the progress table,
+/// which should be in the state the session started from.
+pub fn replay<'a, I>(pt: &mut ProgressTable, records: I) -> Result<(), ReplayError>
+where
+    I: IntoIterator<Item = &'a LogRecord>,
+{
+    for (i, record) in records.into_iter().enumerate() {
+        pt.set_age(record.age);
+        pt.set_leitner_round(record.leitner_round);
+        if let Some((idx, entry)) = record.entry {
+            if idx >= pt.len() {
+                return Err(ReplayError::OutOfRange { record: i, idx });
+            }
+            pt.put_entry(idx, entry);
+        }
+    }
+    Ok(())
+}
+
+/// Reconstructs the progress table from the progress file
+/// the session started from and the session log.
+pub fn replay_file(
+    entries: &[TableEntry],
+    progress: &Path,
+    log: &Path,
+) -> Result<ProgressTable, ReplayError> {
+    let mut pt = ProgressTable::new_from_file(entries, progress)?;
+    let records = read_log(File::open(log)?)?;
+    replay(&mut pt, &records)?;
+    Ok(pt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ent_ex::{ProgressTableView, Score, ScoreArgs, Side};
+    use crate::sim_ex::{SimArgs, Simulation, TMessage};
+    use std::sync::Arc;
+
+    #[test]
+    fn unterminated_last_line_is_ignored() {
+        let record = LogRecord {
+            time: 0,
+            event: LogEvent::Start { seed: Some(1) },
+            age: 0,
+            leitner_round: 0,
+            entry: None,
+        };
+        let mut data = serde_json::to_string(&record).unwrap();
+        data.push('\n');
+        data.push_str("{\"time\":1,\"ev");
+        let records = read_log(data.as_bytes()).ok().unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(matches!(
+            records[0].event,
+            LogEvent::Start { seed: Some(1) }
+        ));
+        data.push('\n');
+        assert!(matches!(
+            read_log(data.as_bytes()),
+            Err(ReplayError::Parse { line: 1, .. })
+        ));
+    }
+
+    /// Continues until a question is pending; returns its entry.
+    fn question(sim: &mut Simulation, topic: &[TableEntry]) -> Idx {
+        for _ in 0..100 {
+            if let Some(TMessage::Assess(idx)) = sim.pending() {
+                return *idx;
+            }
+            sim.next(topic, Action::Continue).ok().unwrap();
+        }
+        panic!("no question came");
+    }
+
+    #[test]
+    fn replay_reconstructs_the_progress() {
+        let topic: Vec<TableEntry> = (0..3)
+            .map(|i| TableEntry {
+                lhs: format!("q{}", i),
+                rhs: format!("a{}", i),
+                alternatives: Vec::new(),
+                side: Side::Forward,
+                cloze: None,
+            })
+            .collect();
+        let args = ScoreArgs {
+            degrade_factor: 0.8,
+            origin: Score(10000),
+            target: Score(100),
+        };
+        let pt = ProgressTable::new(Arc::pin(topic.clone()), args);
+        let dir = std::env::temp_dir();
+        let progress = dir.join(format!("ar-mintin-{}-replay.json", std::process::id()));
+        let log = dir.join(format!("ar-mintin-{}-replay.jsonl", std::process::id()));
+        let view = serde_json::to_vec(&ProgressTableView::new(&pt, &topic)).unwrap();
+        std::fs::write(&progress, view).unwrap();
+        let _ = std::fs::remove_file(&log);
+
+        let mut sim = Simulation::with_seed(pt, SimArgs::default(), 0);
+        sim.set_log(SessionLog::open(&log).unwrap());
+        let answer = |sim: &mut Simulation, right: bool| {
+            let idx = question(sim, &topic);
+            let text = if right {
+                topic[idx].rhs.clone()
+            } else {
+                String::new()
+            };
+            sim.next(&topic, Action::Answer(text)).ok().unwrap();
+        };
+        answer(&mut sim, false);
+        sim.override_last(&topic, true).ok().unwrap();
+        question(&mut sim, &topic);
+        sim.next(&topic, Action::DontKnow).ok().unwrap();
+        answer(&mut sim, true);
+        assert!(sim.undo().is_some());
+        answer(&mut sim, false);
+        answer(&mut sim, true);
+        assert!(sim.log().unwrap().error().is_none());
+
+        let replayed = replay_file(&topic, &progress, &log);
+        std::fs::remove_file(&progress).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        let replayed = replayed.ok().unwrap();
+        assert!(sim.pt.get_unpassed_entries_count() < 3);
+        assert_eq!(
+            replayed.get_unpassed_entries_count(),
+            sim.pt.get_unpassed_entries_count()
+        );
+        assert_eq!(replayed.get_age(), sim.pt.get_age());
+        assert_eq!(
+            serde_json::to_value(&replayed.entries).unwrap(),
+            serde_json::to_value(&sim.pt.entries).unwrap()
+        );
+    }
+}
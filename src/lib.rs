@@ -17,16 +17,27 @@
  *
  */
 
+extern crate csv;
 extern crate json;
 extern crate rand;
+extern crate rand_chacha;
 extern crate serde;
 extern crate serde_json;
+extern crate unicode_normalization;
 
+pub mod clock;
+pub mod cloze;
+pub mod diff;
 pub mod ent;
 pub mod ent_ex;
 pub mod file;
+pub mod file_csv;
 pub mod file_ex;
+pub mod grade;
+pub mod journal;
+pub mod norm;
 mod ostree;
+pub mod sched;
 pub mod sim;
 pub mod sim_ex;
 
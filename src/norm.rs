@@ -0,0 +1,166 @@
+/*
+ * norm.rs -- Normalisation of answers before comparison
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnicodeForm {
+    Nfc,
+    Nfkc,
+}
+
+/// Describes how the answers are normalised before comparison.
+/// The steps are applied in the order of the fields.
+/// The default normaliser leaves the answers intact.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnswerNormalizer {
+    pub unicode: Option<UnicodeForm>,
+    /// Removes accents and other combining marks (e.g. `ą` becomes `a`).
+    pub strip_diacritics: bool,
+    pub case_fold: bool,
+    /// Removes all characters which are neither letters, digits nor whitespace.
+    pub strip_punctuation: bool,
+    /// Replaces every run of whitespace with a single space.
+    pub collapse_whitespace: bool,
+    pub trim: bool,
+}
+
+impl AnswerNormalizer {
+    /// A normaliser forgiving the most common formatting mistakes:
+    /// surrounding and repeated whitespace, and letter case.
+    pub fn lenient() -> AnswerNormalizer {
+        AnswerNormalizer {
+            unicode: Some(UnicodeForm::Nfc),
+            case_fold: true,
+            collapse_whitespace: true,
+            trim: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn normalize(&self, text: &str) -> String {
+        let mut s: String = match self.unicode {
+            None => String::from(text),
+            Some(UnicodeForm::Nfc) => text.nfc().collect(),
+            Some(UnicodeForm::Nfkc) => text.nfkc().collect(),
+        };
+        if self.strip_diacritics {
+            s = s.nfd().filter(|&c| !is_combining_mark(c)).nfc().collect();
+        }
+        if self.case_fold {
+            s = s.to_lowercase();
+        }
+        if self.strip_punctuation {
+            s.retain(|c| c.is_alphanumeric() || c.is_whitespace() || is_combining_mark(c));
+        }
+        if self.collapse_whitespace {
+            let mut prev_ws = false;
+            s = s
+                .chars()
+                .filter_map(|c| {
+                    let ws = c.is_whitespace();
+                    let skip = ws && prev_ws;
+                    prev_ws = ws;
+                    match (skip, ws) {
+                        (true, _) => None,
+                        (false, true) => Some(' '),
+                        (false, false) => Some(c),
+                    }
+                })
+                .collect();
+        }
+        if self.trim {
+            s = String::from(s.trim());
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(step: fn(&mut AnswerNormalizer)) -> AnswerNormalizer {
+        let mut norm = AnswerNormalizer::default();
+        step(&mut norm);
+        norm
+    }
+
+    #[test]
+    fn default_leaves_the_text_intact() {
+        let text = "  Ą,  b\u{328}ﬁ ";
+        assert_eq!(AnswerNormalizer::default().normalize(text), text);
+    }
+
+    #[test]
+    fn whitespace_steps() {
+        let trim = only(|x| x.trim = true);
+        assert_eq!(trim.normalize(" \ta  b\n"), "a  b");
+        let collapse = only(|x| x.collapse_whitespace = true);
+        assert_eq!(collapse.normalize("  a \t\n b "), " a b ");
+    }
+
+    #[test]
+    fn case_fold() {
+        let norm = only(|x| x.case_fold = true);
+        assert_eq!(norm.normalize("ŽODIS Word"), "žodis word");
+    }
+
+    #[test]
+    fn punctuation_is_stripped_but_combining_marks_are_kept() {
+        let norm = only(|x| x.strip_punctuation = true);
+        assert_eq!(norm.normalize("labas, rytas!"), "labas rytas");
+        assert_eq!(norm.normalize("a\u{328}?"), "a\u{328}");
+    }
+
+    #[test]
+    fn unicode_forms() {
+        let nfc = only(|x| x.unicode = Some(UnicodeForm::Nfc));
+        assert_eq!(nfc.normalize("a\u{328}"), "\u{105}");
+        assert_eq!(nfc.normalize("ﬁ"), "ﬁ");
+        let nfkc = only(|x| x.unicode = Some(UnicodeForm::Nfkc));
+        assert_eq!(nfkc.normalize("ﬁ"), "fi");
+    }
+
+    #[test]
+    fn diacritics_are_stripped() {
+        let norm = only(|x| x.strip_diacritics = true);
+        assert_eq!(norm.normalize("ąčęėįšųūž"), "aceeisuuz");
+        assert_eq!(norm.normalize("a\u{328}"), "a");
+    }
+
+    #[test]
+    fn steps_run_in_field_order() {
+        // Punctuation is stripped before the whitespace is collapsed and trimmed.
+        let norm = AnswerNormalizer {
+            strip_punctuation: true,
+            collapse_whitespace: true,
+            trim: true,
+            ..AnswerNormalizer::default()
+        };
+        assert_eq!(norm.normalize("! a , b ."), "a b");
+        assert_eq!(
+            AnswerNormalizer::lenient().normalize("  Labas   RYTAS "),
+            "labas rytas"
+        );
+    }
+}
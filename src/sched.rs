@@ -0,0 +1,531 @@
+/*
+ * sched.rs -- Scheduling algorithms for the progress table
+ * Copyright (C) 2022 Arnoldas Rauba
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+use crate::clock::Timestamp;
+use crate::ent_ex::{ProgressEntry, Score, UNIT};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// State of the progress table at the moment of scheduling.
+#[derive(Clone, Copy, Debug)]
+pub struct SchedContext {
+    pub age: i32,
+    /// See `ProgressTable::unit_score`.
+    pub unit: Score,
+    pub now: Timestamp,
+}
+
+/// Decides how the assessments change the progress entries
+/// and how likely each entry is to be selected.
+/// The `pass` flag of the entry is maintained by the progress table.
+pub trait Scheduler: fmt::Debug + Send + Sync {
+    /// Updates the entry after an assessment.
+    /// `entry.pass` and the timestamps still hold the previous values.
+    /// Returns the sampling weight of the entry until its next update.
+    fn update(&self, entry: &mut ProgressEntry, pass: bool, ctx: &SchedContext) -> i64;
+
+    /// Sampling weight of an entry which has not been updated yet
+    /// in this session (e.g. loaded from a file).
+    /// An entry of weight 0 is not selected (e.g. because it is not due).
+    fn weight(&self, entry: &ProgressEntry, ctx: &SchedContext) -> i64;
+
+    /// Updates the entry after an assessment graded by quality from 0 to 5.
+    /// By default, the grades of 3 and above count as a pass.
+    fn grade(&self, entry: &mut ProgressEntry, quality: u8, ctx: &SchedContext) -> i64 {
+        self.update(entry, quality >= 3, ctx)
+    }
+
+    /// Prepares an entry written by another algorithm.
+    fn prepare(&self, _entry: &mut ProgressEntry, _ctx: &SchedContext) {}
+
+    /// Whether the weights change with age or time,
+    /// so that they are recomputed before every selection.
+    fn time_dependent(&self) -> bool {
+        false
+    }
+}
+
+/// The original algorithm:
+/// the distrust halves on pass and is pulled towards the unit score on fail.
+/// The entry is sampled by its distrust before the update.
+///
+/// Graded assessments scale the update: a pass of quality 3 divides the distrust
+/// by the square root of 2, of quality 5 by 4; a fail of quality 0 pulls it
+/// closer to the unit score, of quality 2 less close.
+/// A plain pass or fail weighs as much as the quality of 4 or 1 respectively.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DistrustScheduler;
+
+impl DistrustScheduler {
+    /// Exponents of the fail smoothing, by quality.
+    const SMOOTH_F: [f64; 3] = [0.25, 0.5, 0.75];
+    /// Powers of 2 dividing the distrust on pass, by quality minus 3.
+    const PASS_EXP: [f64; 3] = [0.5, 1.0, 2.0];
+}
+
+impl Scheduler for DistrustScheduler {
+    fn update(&self, entry: &mut ProgressEntry, pass: bool, ctx: &SchedContext) -> i64 {
+        self.grade(entry, if pass { 4 } else { 1 }, ctx)
+    }
+
+    fn grade(&self, entry: &mut ProgressEntry, quality: u8, ctx: &SchedContext) -> i64 {
+        let us = ctx.unit.0 as f64;
+        let dt0 = entry.distrust;
+        entry.distrust = match quality.min(5) {
+            4 => Score((dt0.0 + 1) / 2),
+            q @ 3..=5 => {
+                let d = 2_f64.powf(Self::PASS_EXP[q as usize - 3]);
+                Score((dt0.0 as f64 / d).ceil() as i64)
+            }
+            q => {
+                let a: f64 = ((dt0.0 as f64) / us).powf(Self::SMOOTH_F[q as usize]);
+                Score((us * a) as i64)
+            }
+        };
+        dt0.0
+    }
+
+    fn weight(&self, entry: &ProgressEntry, _: &SchedContext) -> i64 {
+        entry.distrust.0
+    }
+}
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+/// Days since the last review of the entry, if it has been reviewed.
+fn days_since_review(entry: &ProgressEntry, ctx: &SchedContext) -> Option<f64> {
+    entry
+        .last_reviewed
+        .map(|t| ctx.now.saturating_sub(t) as f64 / SECONDS_PER_DAY)
+}
+
+/// Per-entry state of the SM-2 algorithm.
+/// The entry is due `interval` days after `ProgressEntry::last_reviewed`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sm2State {
+    pub ease: f64,
+    pub repetitions: u32,
+    /// In days.
+    pub interval: u32,
+}
+
+impl Sm2State {
+    pub const INITIAL_EASE: f64 = 2.5;
+    pub const MIN_EASE: f64 = 1.3;
+
+    pub fn new() -> Sm2State {
+        Sm2State {
+            ease: Self::INITIAL_EASE,
+            repetitions: 0,
+            interval: 0,
+        }
+    }
+
+    /// Estimates the state from the distrust model:
+    /// every halving of the distrust of a passed entry below the unit score
+    /// counts as one repetition; failed entries start anew.
+    pub fn seed(entry: &ProgressEntry, ctx: &SchedContext) -> Sm2State {
+        let unit = ctx.unit.0.max(1) as f64;
+        let ratio = (entry.distrust.0.max(1) as f64 / unit).min(1.0);
+        let mut state = Sm2State::new();
+        if entry.pass {
+            let repetitions = (-ratio.log2()).round().clamp(1.0, 10.0) as u32;
+            for _ in 0..repetitions {
+                state.repetitions += 1;
+                state.interval = state.next_interval();
+            }
+        }
+        state
+    }
+
+    fn next_interval(&self) -> u32 {
+        match self.repetitions {
+            0 | 1 => 1,
+            2 => 6,
+            _ => (self.interval as f64 * self.ease).round() as u32,
+        }
+    }
+
+    /// Applies an assessment of the given quality (0 to 5).
+    pub fn review(&mut self, quality: u8) {
+        let q = quality.min(5) as f64;
+        if quality >= 3 {
+            self.repetitions += 1;
+            self.interval = self.next_interval();
+        } else {
+            self.repetitions = 0;
+            self.interval = 1;
+        }
+        self.ease = (self.ease + (0.1 - (5.0 - q) * (0.08 + (5.0 - q) * 0.02))).max(Self::MIN_EASE);
+    }
+}
+
+impl Default for Sm2State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The SM-2 algorithm of SuperMemo.
+/// Passed entries past their interval are sampled in proportion to how overdue they are;
+/// the others are not due and have the weight of 0,
+/// so an assessment session may draw fewer entries or none at all.
+/// Entries never reviewed and failed entries are always due.
+/// The distrust is still maintained as by `DistrustScheduler`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sm2Scheduler;
+
+impl Sm2Scheduler {
+    /// Quality assumed for a plain pass or fail.
+    const PASS_QUALITY: u8 = 4;
+    const FAIL_QUALITY: u8 = 1;
+}
+
+impl Scheduler for Sm2Scheduler {
+    fn update(&self, entry: &mut ProgressEntry, pass: bool, ctx: &SchedContext) -> i64 {
+        let quality = if pass {
+            Self::PASS_QUALITY
+        } else {
+            Self::FAIL_QUALITY
+        };
+        self.grade(entry, quality, ctx)
+    }
+
+    fn grade(&self, entry: &mut ProgressEntry, quality: u8, ctx: &SchedContext) -> i64 {
+        let mut state = entry.sm2.unwrap_or_else(|| Sm2State::seed(entry, ctx));
+        state.review(quality);
+        entry.sm2 = Some(state);
+        DistrustScheduler.update(entry, quality >= 3, ctx);
+        // Just reviewed: due only if failed.
+        if quality >= 3 {
+            0
+        } else {
+            UNIT.0
+        }
+    }
+
+    fn weight(&self, entry: &ProgressEntry, ctx: &SchedContext) -> i64 {
+        match (entry.sm2, days_since_review(entry, ctx)) {
+            (Some(state), Some(elapsed)) if entry.pass => {
+                let interval = state.interval as f64;
+                if elapsed >= interval {
+                    UNIT.0 + (UNIT.0 as f64 * (elapsed - interval) / interval.max(1.0)) as i64
+                } else {
+                    0
+                }
+            }
+            _ => UNIT.0,
+        }
+    }
+
+    fn prepare(&self, entry: &mut ProgressEntry, ctx: &SchedContext) {
+        if entry.sm2.is_none() {
+            entry.sm2 = Some(Sm2State::seed(entry, ctx));
+        }
+    }
+
+    fn time_dependent(&self) -> bool {
+        true
+    }
+}
+
+/// Per-entry state of the FSRS algorithm.
+/// Times are measured in days; the time of the last review
+/// is `ProgressEntry::last_reviewed`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FsrsState {
+    /// The time for the retrievability to drop to 90%.
+    pub stability: f64,
+    /// From 1 (easy) to 10 (hard).
+    pub difficulty: f64,
+}
+
+/// The Free Spaced Repetition Scheduler (FSRS-4.5).
+/// Passed entries whose retrievability dropped below `desired_retention` are sampled
+/// in proportion to the drop; the others are not due and have the weight of 0,
+/// so an assessment session may draw fewer entries or none at all.
+/// Failed entries are always due for learning.
+/// The distrust is still maintained as by `DistrustScheduler`.
+#[derive(Clone, Copy, Debug)]
+pub struct FsrsScheduler {
+    pub desired_retention: f64,
+    pub weights: [f64; 17],
+}
+
+impl Default for FsrsScheduler {
+    fn default() -> Self {
+        FsrsScheduler {
+            desired_retention: 0.9,
+            weights: Self::DEFAULT_WEIGHTS,
+        }
+    }
+}
+
+impl FsrsScheduler {
+    pub const DEFAULT_WEIGHTS: [f64; 17] = [
+        0.4872, 1.4003, 3.7145, 13.8206, 5.1618, 1.2298, 0.8975, 0.031, 1.6474, 0.1367, 1.0461,
+        2.1072, 0.0793, 0.3246, 1.587, 0.2272, 2.8755,
+    ];
+    const DECAY: f64 = -0.5;
+    const FACTOR: f64 = 19.0 / 81.0;
+
+    /// Converts the quality (0 to 5) to the FSRS rating
+    /// (1 again, 2 hard, 3 good, 4 easy).
+    fn rating(quality: u8) -> u8 {
+        match quality {
+            0..=2 => 1,
+            3 => 2,
+            4 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Days since the last review of the entry;
+    /// infinite if it has never been reviewed, so that it counts as forgotten.
+    pub fn elapsed_days(entry: &ProgressEntry, ctx: &SchedContext) -> f64 {
+        days_since_review(entry, ctx).unwrap_or(f64::INFINITY)
+    }
+
+    /// The probability of recall after `elapsed` days.
+    pub fn retrievability(&self, state: &FsrsState, elapsed: f64) -> f64 {
+        (1.0 + Self::FACTOR * elapsed.max(0.0) / state.stability).powf(Self::DECAY)
+    }
+
+    /// The days until the retrievability drops to `desired_retention`.
+    pub fn interval(&self, state: &FsrsState) -> f64 {
+        state.stability / Self::FACTOR * (self.desired_retention.powf(1.0 / Self::DECAY) - 1.0)
+    }
+
+    fn initial_difficulty(&self, rating: u8) -> f64 {
+        let w = &self.weights;
+        (w[4] - (rating as f64 - 3.0) * w[5]).clamp(1.0, 10.0)
+    }
+
+    /// Applies an assessment of the given quality (0 to 5)
+    /// made `elapsed` days after the previous one.
+    pub fn review(&self, state: Option<FsrsState>, quality: u8, elapsed: f64) -> FsrsState {
+        let w = &self.weights;
+        let g = Self::rating(quality);
+        let state = match state {
+            None => {
+                return FsrsState {
+                    stability: w[g as usize - 1],
+                    difficulty: self.initial_difficulty(g),
+                }
+            }
+            Some(state) => state,
+        };
+        let r = self.retrievability(&state, elapsed);
+        let (s, d) = (state.stability, state.difficulty);
+        let next_d = d - w[6] * (g as f64 - 3.0);
+        let difficulty =
+            (w[7] * self.initial_difficulty(3) + (1.0 - w[7]) * next_d).clamp(1.0, 10.0);
+        let stability = if g == 1 {
+            w[11] * d.powf(-w[12]) * ((s + 1.0).powf(w[13]) - 1.0) * (w[14] * (1.0 - r)).exp()
+        } else {
+            let hard = if g == 2 { w[15] } else { 1.0 };
+            let easy = if g == 4 { w[16] } else { 1.0 };
+            s * (w[8].exp()
+                * (11.0 - d)
+                * s.powf(-w[9])
+                * ((w[10] * (1.0 - r)).exp() - 1.0)
+                * hard
+                * easy
+                + 1.0)
+        };
+        FsrsState {
+            stability: stability.max(0.01),
+            difficulty,
+        }
+    }
+}
+
+impl Scheduler for FsrsScheduler {
+    fn update(&self, entry: &mut ProgressEntry, pass: bool, ctx: &SchedContext) -> i64 {
+        self.grade(entry, if pass { 4 } else { 1 }, ctx)
+    }
+
+    fn grade(&self, entry: &mut ProgressEntry, quality: u8, ctx: &SchedContext) -> i64 {
+        let elapsed = Self::elapsed_days(entry, ctx);
+        entry.fsrs = Some(self.review(entry.fsrs, quality, elapsed));
+        DistrustScheduler.update(entry, quality >= 3, ctx);
+        // Just reviewed: due only if failed.
+        if quality >= 3 {
+            0
+        } else {
+            UNIT.0
+        }
+    }
+
+    fn weight(&self, entry: &ProgressEntry, ctx: &SchedContext) -> i64 {
+        match entry.fsrs {
+            Some(state) if entry.pass => {
+                let r = self.retrievability(&state, Self::elapsed_days(entry, ctx));
+                if r < self.desired_retention {
+                    UNIT.0
+                        + (UNIT.0 as f64 * (self.desired_retention - r) / self.desired_retention)
+                            as i64
+                } else {
+                    0
+                }
+            }
+            _ => UNIT.0,
+        }
+    }
+
+    /// Seeds the passed entries: the stability is the number of halvings
+    /// of the distrust below the unit score, and the difficulty is average.
+    /// Other entries are treated as new.
+    fn prepare(&self, entry: &mut ProgressEntry, ctx: &SchedContext) {
+        if entry.fsrs.is_none() && entry.pass {
+            let ratio = entry.distrust.0.max(1) as f64 / ctx.unit.0.max(1) as f64;
+            entry.fsrs = Some(FsrsState {
+                stability: (-ratio.log2()).max(1.0),
+                difficulty: self.initial_difficulty(3),
+            });
+        }
+    }
+
+    fn time_dependent(&self) -> bool {
+        true
+    }
+}
+
+/// Multiplies the weights of another scheduler by a factor
+/// which doubles every `half_life` seconds since the last review,
+/// so that the entries not reviewed for a long time are preferred.
+/// The factor is capped at `MAX_FACTOR`.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeDecay<S> {
+    pub inner: S,
+    pub half_life: Timestamp,
+}
+
+impl<S> TimeDecay<S> {
+    pub const MAX_FACTOR: f64 = 64.0;
+
+    pub fn factor(&self, entry: &ProgressEntry, ctx: &SchedContext) -> f64 {
+        match entry.last_reviewed {
+            Some(t) if self.half_life > 0 => {
+                let elapsed = ctx.now.saturating_sub(t) as f64;
+                (elapsed / self.half_life as f64)
+                    .exp2()
+                    .min(Self::MAX_FACTOR)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+impl<S: Scheduler> Scheduler for TimeDecay<S> {
+    fn update(&self, entry: &mut ProgressEntry, pass: bool, ctx: &SchedContext) -> i64 {
+        self.inner.update(entry, pass, ctx)
+    }
+
+    fn weight(&self, entry: &ProgressEntry, ctx: &SchedContext) -> i64 {
+        (self.inner.weight(entry, ctx) as f64 * self.factor(entry, ctx)) as i64
+    }
+
+    fn grade(&self, entry: &mut ProgressEntry, quality: u8, ctx: &SchedContext) -> i64 {
+        self.inner.grade(entry, quality, ctx)
+    }
+
+    fn prepare(&self, entry: &mut ProgressEntry, ctx: &SchedContext) {
+        self.inner.prepare(entry, ctx)
+    }
+
+    fn time_dependent(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Timestamp = 86400;
+
+    fn ctx(now: Timestamp) -> SchedContext {
+        SchedContext {
+            age: 0,
+            unit: UNIT,
+            now,
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn sm2_ease_follows_the_quality() {
+        for (quality, ease) in [(5, 2.6), (4, 2.5), (3, 2.36), (1, 1.96), (0, 1.7)] {
+            let mut state = Sm2State::new();
+            state.review(quality);
+            assert!(close(state.ease, ease), "{} {}", quality, state.ease);
+        }
+        let mut state = Sm2State::new();
+        for _ in 0..10 {
+            state.review(0);
+        }
+        assert_eq!(state.ease, Sm2State::MIN_EASE);
+    }
+
+    #[test]
+    fn sm2_intervals_grow_and_reset_on_fail() {
+        let mut state = Sm2State::new();
+        let mut intervals = Vec::new();
+        for _ in 0..4 {
+            state.review(4);
+            intervals.push(state.interval);
+        }
+        assert_eq!(intervals, [1, 6, 15, 38]);
+        state.review(2);
+        assert_eq!((state.repetitions, state.interval), (0, 1));
+        state.review(4);
+        assert_eq!(state.interval, 1);
+    }
+
+    #[test]
+    fn sm2_seed_counts_halvings_of_passed_entries() {
+        let mut entry = ProgressEntry::new(Score(UNIT.0 / 4));
+        assert_eq!(Sm2State::seed(&entry, &ctx(0)), Sm2State::new());
+        entry.pass = true;
+        let state = Sm2State::seed(&entry, &ctx(0));
+        assert_eq!((state.repetitions, state.interval), (2, 6));
+        entry.distrust = UNIT;
+        let state = Sm2State::seed(&entry, &ctx(0));
+        assert_eq!((state.repetitions, state.interval), (1, 1));
+    }
+
+    #[test]
+    fn sm2_entries_are_due_after_the_interval() {
+        let mut entry = ProgressEntry::new(UNIT);
+        assert_eq!(Sm2Scheduler.weight(&entry, &ctx(0)), UNIT.0);
+        assert_eq!(Sm2Scheduler.update(&mut entry, true, &ctx(0)), 0);
+        entry.pass = true;
+        entry.last_reviewed = Some(0);
+        let interval = entry.sm2.unwrap().interval as Timestamp;
+        assert_eq!(Sm2Scheduler.weight(&entry, &ctx(interval * DAY - 1)), 0);
+        assert_eq!(Sm2Scheduler.weight(&entry, &ctx(interval * DAY)), UNIT.0);
+        assert!(Sm2Scheduler.weight(&entry, &ctx(3 * interval * DAY)) > UNIT.0);
+        entry.pass = false;
+        assert_eq!(Sm2Scheduler.weight(&entry, &ctx(0)), UNIT.0);
+    }
+}
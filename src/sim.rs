@@ -85,7 +85,7 @@ impl<'a> Simulation<'a> {
         }
     }
 
-    pub fn next(&mut self, post: Option<String>) -> UiMessage {
+    pub fn next(&mut self, post: Option<String>) -> UiMessage<'_> {
         assert_eq!(
             matches!(self.last_msg, Some(TMessage::Assess(_))),
             post.is_some()
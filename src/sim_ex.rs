@@ -17,101 +17,692 @@
  *
  */
 
+use crate::diff::AnswerDiff;
+use crate::ent_ex::EntrySnapshot;
 use crate::ent_ex::ProgressTable;
 use crate::ent_ex::Score;
 use crate::ent_ex::TableEntry;
+use crate::grade::{Grade, TypoPolicy, TypoTolerance, Verdict};
+use crate::journal::{LogEvent, LogRecord, SessionLog};
+use crate::norm::AnswerNormalizer;
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
 
 pub struct SimArgs {
     /// Simulate classic mode
     /// (no rehearsal of the learned sentence)
     pub classic: bool,
+    /// Normalisation applied to the answers before comparison.
+    /// Usually taken from `Deck::normalizer`.
+    pub normalizer: AnswerNormalizer,
+    pub typo_tolerance: TypoTolerance,
+    /// Whether the answers accepted with a typo are passed to the progress table as passes.
+    pub typo_policy: TypoPolicy,
+    pub mode: SimMode,
+    pub questions: QuestionMode,
+    pub sessions: SessionSizes,
+    /// Number of answers which can be undone.
+    pub undo_limit: usize,
 }
 
+impl Default for SimArgs {
+    fn default() -> Self {
+        SimArgs {
+            classic: false,
+            normalizer: AnswerNormalizer::default(),
+            typo_tolerance: TypoTolerance::default(),
+            typo_policy: TypoPolicy::default(),
+            mode: SimMode::default(),
+            questions: QuestionMode::default(),
+            sessions: SessionSizes::default(),
+            undo_limit: 16,
+        }
+    }
+}
+
+/// Numbers of entries drawn by the sessions.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionSizes {
+    /// Passed entries assessed by an assessment session.
+    pub assess: usize,
+    /// Unpassed entries taught by a learning session.
+    pub learn: usize,
+    /// Passed entries rehearsed after each taught entry.
+    pub rehearse: usize,
+    /// Shrink the sessions for small tables:
+    /// an assessment session takes at most half of the passed entries,
+    /// and a learning session at most a quarter of the unpassed entries
+    /// (at least one entry in both cases).
+    pub adaptive: bool,
+}
+
+impl SessionSizes {
+    pub fn assess_size(&self, pt: &ProgressTable) -> usize {
+        if self.adaptive {
+            let passed = pt.len() - pt.get_unpassed_entries_count();
+            self.assess.min(passed.div_ceil(2).max(1))
+        } else {
+            self.assess
+        }
+    }
+
+    pub fn learn_size(&self, pt: &ProgressTable) -> usize {
+        if self.adaptive {
+            self.learn
+                .min(pt.get_unpassed_entries_count().div_ceil(4).max(1))
+        } else {
+            self.learn
+        }
+    }
+}
+
+impl Default for SessionSizes {
+    fn default() -> Self {
+        SessionSizes {
+            assess: 10,
+            learn: 10,
+            rehearse: 1,
+            adaptive: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum SimMode {
+    /// Alternate between assessment and learning sessions.
+    #[default]
+    Alternating,
+    Leitner(LeitnerArgs),
+}
+
+/// How the user responds to `Assess`.
+#[derive(Clone, Debug, Default)]
+pub enum QuestionMode {
+    /// The answer is typed (`Action::Answer`).
+    #[default]
+    Typed,
+    /// Flip cards: `Action::Continue` reveals the answer (`Reveal`),
+    /// which is then graded by the user (`Action::Grade`).
+    SelfGraded,
+    /// `Assess` is replaced by `Choose`, answered by `Action::Choose`.
+    Choice(ChoiceArgs),
+}
+
+/// Arguments of the multiple-choice questions.
 #[derive(Clone, Debug)]
+pub struct ChoiceArgs {
+    /// Number of wrong options; fewer if the topic lacks distinct answers.
+    pub distractors: usize,
+}
+
+impl Default for ChoiceArgs {
+    fn default() -> Self {
+        ChoiceArgs { distractors: 3 }
+    }
+}
+
+/// Arguments of the Leitner system.
+/// Every round (session) assesses the entries of the boxes due in it;
+/// box `k` (counting from 1) is due in the rounds divisible by `frequencies[k - 1]`.
+/// Rounds are counted by the progress table (see `ProgressTable::get_leitner_round`),
+/// which is stepped after every round.
+#[derive(Clone, Debug)]
+pub struct LeitnerArgs {
+    pub frequencies: Vec<u32>,
+}
+
+impl LeitnerArgs {
+    pub fn boxes(&self) -> u8 {
+        self.frequencies.len().clamp(1, u8::MAX as usize) as u8
+    }
+}
+
+impl Default for LeitnerArgs {
+    fn default() -> Self {
+        LeitnerArgs {
+            frequencies: vec![1, 2, 4, 8, 16],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TMessage<T> {
     Assess(T),
     Display(T),
     NotifyAssessment,
+    /// The answer to the assessed entry, to be graded by the user
+    /// (see `QuestionMode::SelfGraded`).
+    Reveal(T),
+    /// Multiple-choice assessment of `idx`:
+    /// the options are entries whose `rhs` is shown, one of them is `idx`
+    /// (see `QuestionMode::Choice`).
+    Choose {
+        idx: T,
+        options: Vec<T>,
+    },
+    /// No entry is left to assess or learn in this round
+    /// (e.g. the others are buried or not due yet).
+    /// `Action::Continue` starts a new round.
+    NothingDue,
 }
 
 pub type UiMessage = TMessage<usize>;
 
+/// The user's response to the pending message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Action {
+    /// Acknowledges `Display`, `NotifyAssessment` or `NothingDue`; also starts the session.
+    /// In self-graded mode, reveals the answer to `Assess`.
+    Continue,
+    /// The answer to `Assess`.
+    Answer(String),
+    /// The self-assessment of `Reveal`.
+    Grade(Grade),
+    /// The position of the selected option of `Choose`.
+    Choose(usize),
+    /// Moves past `Assess` (or `Reveal`, `Choose`) without any change in progress.
+    Skip,
+    /// Counts as a failed `Assess` (or `Reveal`, `Choose`);
+    /// the entry is displayed right away.
+    DontKnow,
+    /// Skips `Assess` (or `Reveal`, `Choose`) and withholds the entry until the next session
+    /// (see `Simulation::flush_state`).
+    /// The last entry of the topic cannot be buried.
+    Bury,
+}
+
 pub struct BadMessageError;
 
 pub struct Simulation {
     pub pt: ProgressTable,
     pub args: SimArgs,
     last_msg: Option<UiMessage>,
-    state: Main,
+    state: Bivariant<Main, Leitner>,
+    rng: Box<dyn RngCore + Send>,
+    seed: Option<u64>,
+    history: VecDeque<UndoRecord>,
+    buried: BTreeSet<usize>,
+    /// The message to emit after the entry displayed on `Action::DontKnow`.
+    queued: Option<UiMessage>,
+    /// Pairs of an entry and the wrong option chosen for it in this session.
+    confusions: BTreeSet<(usize, usize)>,
+    log: Option<SessionLog>,
 }
 
+/// The state of the simulation before an action, and the outcome of an answer.
+struct UndoRecord {
+    snapshot: EntrySnapshot,
+    last_msg: Option<UiMessage>,
+    state: Bivariant<Main, Leitner>,
+    buried: BTreeSet<usize>,
+    change: Option<Change>,
+    /// The option chosen in answer to `Choose`.
+    chosen: Option<usize>,
+}
+
+/// Snapshot of a session in progress, see `Simulation::save_state`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimState {
+    last_msg: Option<UiMessage>,
+    state: Bivariant<Main, Leitner>,
+    #[serde(default)]
+    buried: BTreeSet<usize>,
+    #[serde(default)]
+    queued: Option<UiMessage>,
+    #[serde(default)]
+    confusions: BTreeSet<(usize, usize)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Change {
     pub idx: usize,
     pub pass: bool,
+    pub verdict: Verdict,
+    /// Alignment of the answer with the matched (or canonical) answer,
+    /// unless the answer is exact.
+    pub diff: Option<AnswerDiff>,
     pub distrust: Score,
+    /// The self-assessment; the verdict then follows from it.
+    pub grade: Option<Grade>,
+    /// The user has overridden the verdict (see `Simulation::override_last`);
+    /// `pass` holds the overriding outcome.
+    pub overridden: bool,
 }
 
 impl Simulation {
+    /// Creates a simulation with a random seed (see `seed`).
     pub fn new(pt: ProgressTable, args: SimArgs) -> Simulation {
+        Self::with_seed(pt, args, thread_rng().gen())
+    }
+
+    /// Creates a reproducible simulation:
+    /// the same seed and the same answers yield the same messages.
+    pub fn with_seed(pt: ProgressTable, args: SimArgs, seed: u64) -> Simulation {
+        let mut sim = Self::with_rng(pt, args, Box::new(ChaCha8Rng::seed_from_u64(seed)));
+        sim.seed = Some(seed);
+        sim
+    }
+
+    /// Creates a simulation drawing from the given generator.
+    /// Its seed is unknown to the simulation.
+    pub fn with_rng(pt: ProgressTable, args: SimArgs, rng: Box<dyn RngCore + Send>) -> Simulation {
         Simulation {
+            state: Self::initial_state(&args),
             pt,
             args,
             last_msg: None,
-            state: Main::new(),
+            rng,
+            seed: None,
+            history: VecDeque::new(),
+            buried: BTreeSet::new(),
+            queued: None,
+            confusions: BTreeSet::new(),
+            log: None,
+        }
+    }
+
+    /// The seed of the random number generator, to be recorded in the session logs.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    fn initial_state(args: &SimArgs) -> Bivariant<Main, Leitner> {
+        match args.mode {
+            SimMode::Alternating => Bivariant::V1(Main::new()),
+            SimMode::Leitner(_) => Bivariant::V2(Leitner::new()),
+        }
+    }
+
+    /// Attaches a session log, which records every call from now on
+    /// (see `journal::replay`).
+    pub fn set_log(&mut self, log: SessionLog) {
+        self.log = Some(log);
+        self.write_log(LogEvent::Start { seed: self.seed }, None);
+    }
+
+    pub fn log(&self) -> Option<&SessionLog> {
+        self.log.as_ref()
+    }
+
+    fn write_log(&mut self, event: LogEvent, idx: Option<usize>) {
+        if let Some(log) = &mut self.log {
+            log.append(&LogRecord {
+                time: self.pt.now(),
+                event,
+                age: self.pt.get_age(),
+                leitner_round: self.pt.get_leitner_round(),
+                entry: idx.map(|i| (i, self.pt.entries[i])),
+            });
         }
     }
 
+    /// `topic` holds the cards the progress table was made for;
+    /// use `make_cards` to drill the deck in reverse or in both directions.
+    /// Fails if the action does not respond to the pending message.
     pub fn next(
         &mut self,
         topic: &[TableEntry],
-        post: Option<String>,
+        action: Action,
     ) -> Result<(UiMessage, Option<Change>), BadMessageError> {
-        if matches!(self.last_msg, Some(TMessage::Assess(_))) != post.is_some() {
-            Err(BadMessageError)
-        } else {
-            let change = if let Some(TMessage::Assess(ent)) = self.last_msg {
-                let b = topic[ent].assess(post.unwrap());
-                self.pt.set(ent, b);
-                Some(Change {
-                    idx: ent,
-                    pass: b,
-                    distrust: self.pt.entries[ent].distrust,
-                })
-            } else {
-                None
-            };
-            let inp = &mut Input {
-                pt: &mut self.pt,
-                args: &self.args,
+        let logged = self.log.is_some().then(|| action.clone());
+        let (message, change) = self.respond(topic, action)?;
+        if let Some(action) = logged {
+            let event = LogEvent::Next {
+                action,
+                message: message.clone(),
+                change: change.clone(),
             };
+            self.write_log(event, change.as_ref().map(|x| x.idx));
+        }
+        Ok((message, change))
+    }
 
-            let r = self.state.next(
-                inp,
-                match &change {
-                    None => false,
-                    Some(a) => a.pass,
+    fn respond(
+        &mut self,
+        topic: &[TableEntry],
+        action: Action,
+    ) -> Result<(UiMessage, Option<Change>), BadMessageError> {
+        let self_graded = matches!(self.args.questions, QuestionMode::SelfGraded);
+        let typed = matches!(self.args.questions, QuestionMode::Typed);
+        let ent = match (&self.last_msg, &action) {
+            (Some(TMessage::Assess(ent)), Action::Continue) if self_graded => {
+                let msg = TMessage::Reveal(*ent);
+                self.last_msg = Some(msg.clone());
+                return Ok((msg, None));
+            }
+            (
+                Some(TMessage::Assess(_) | TMessage::Reveal(_) | TMessage::Choose { .. }),
+                Action::Continue,
+            ) => return Err(BadMessageError),
+            (Some(TMessage::Assess(ent)), Action::Answer(_)) if typed => *ent,
+            (Some(TMessage::Reveal(ent)), Action::Grade(_)) => *ent,
+            (Some(TMessage::Choose { idx, options }), Action::Choose(i)) if *i < options.len() => {
+                *idx
+            }
+            (
+                Some(TMessage::Assess(_) | TMessage::Reveal(_) | TMessage::Choose { .. }),
+                Action::Bury,
+            ) if self.buried.len() + 1 >= self.pt.len() => return Err(BadMessageError),
+            (
+                Some(
+                    TMessage::Assess(ent)
+                    | TMessage::Reveal(ent)
+                    | TMessage::Choose { idx: ent, .. },
+                ),
+                Action::Skip | Action::DontKnow | Action::Bury,
+            ) => *ent,
+            (_, Action::Continue) => {
+                if let Some(msg) = self.queued.take() {
+                    self.last_msg = Some(msg.clone());
+                    return Ok((msg, None));
+                }
+                return Ok((self.advance(topic, false), None));
+            }
+            _ => return Err(BadMessageError),
+        };
+        let mut record = UndoRecord {
+            snapshot: self.pt.save_entry(ent),
+            last_msg: self.last_msg.clone(),
+            state: self.state.clone(),
+            buried: self.buried.clone(),
+            change: None,
+            chosen: None,
+        };
+        let change = match action {
+            Action::Answer(ref answer) => Some(self.assess(topic, ent, answer)),
+            Action::Choose(i) => {
+                let chosen = match &self.last_msg {
+                    Some(TMessage::Choose { options, .. }) => options[i],
+                    _ => unreachable!(),
+                };
+                record.chosen = Some(chosen);
+                let change = self.assess(topic, ent, &topic[chosen].rhs);
+                if !change.pass {
+                    self.confusions.insert((ent, chosen));
+                }
+                Some(change)
+            }
+            Action::Grade(grade) => Some(Change {
+                idx: ent,
+                pass: grade.pass(),
+                verdict: if grade.pass() {
+                    Verdict::Exact { answer: 0 }
+                } else {
+                    Verdict::Wrong
                 },
-                1,
-            );
-            if cfg!(sim_debug) {
-                eprintln!();
-                eprintln!();
+                diff: None,
+                distrust: self.apply(ent, grade.pass(), Some(grade)),
+                grade: Some(grade),
+                overridden: false,
+            }),
+            Action::DontKnow => Some(Change {
+                idx: ent,
+                pass: false,
+                verdict: Verdict::Wrong,
+                diff: None,
+                distrust: self.apply(ent, false, None),
+                grade: None,
+                overridden: false,
+            }),
+            Action::Bury => {
+                self.buried.insert(ent);
+                None
             }
-            self.last_msg = r.clone();
-            Ok((r.unwrap(), change))
+            Action::Skip | Action::Continue => None,
+        };
+        self.remember(UndoRecord {
+            change: change.clone(),
+            ..record
+        });
+        let r = match &change {
+            // A skipped entry is passed over as if answered correctly
+            None => self.advance(topic, true),
+            Some(a) => self.advance(topic, a.pass),
+        };
+        if matches!(action, Action::DontKnow) && !matches!(r, TMessage::Display(x) if x == ent) {
+            self.queued = Some(r);
+            self.last_msg = Some(TMessage::Display(ent));
+            return Ok((TMessage::Display(ent), change));
         }
+        Ok((r, change))
     }
 
+    /// Grades an answer typed or chosen by the user.
+    fn assess(&mut self, topic: &[TableEntry], ent: usize, answer: &str) -> Change {
+        let verdict =
+            topic[ent].assess_with(answer, &self.args.normalizer, &self.args.typo_tolerance);
+        let diff = match verdict {
+            Verdict::Exact { .. } => None,
+            _ => {
+                let idx = verdict.answer().unwrap_or(0);
+                let expected = topic[ent].answers().nth(idx).unwrap();
+                Some(AnswerDiff::new(answer, expected, idx))
+            }
+        };
+        let b = verdict.pass(self.args.typo_policy);
+        Change {
+            idx: ent,
+            pass: b,
+            verdict,
+            diff,
+            distrust: self.apply(ent, b, None),
+            grade: None,
+            overridden: false,
+        }
+    }
+
+    /// Picks up to `k` entries whose answers can be mistaken for the answer to `ent`:
+    /// the ones confused with it earlier in the session first,
+    /// then the ones closest in length.
+    /// Answers which would be accepted for `ent` and duplicates are left out.
+    fn distractors(&mut self, topic: &[TableEntry], ent: usize, k: usize) -> Vec<usize> {
+        let target = &topic[ent];
+        let len = target.rhs.chars().count();
+        let mut candidates: Vec<usize> = (0..topic.len())
+            .filter(|&i| {
+                i != ent
+                    && topic[i].side == target.side
+                    && topic[i].cloze.is_some() == target.cloze.is_some()
+            })
+            .collect();
+        candidates.shuffle(&mut self.rng);
+        candidates.sort_by_key(|&i| {
+            (
+                !self.confusions.contains(&(ent, i)),
+                topic[i].rhs.chars().count().abs_diff(len),
+            )
+        });
+        let mut seen = vec![self.args.normalizer.normalize(&target.rhs)];
+        let mut r = Vec::new();
+        for i in candidates {
+            if r.len() == k {
+                break;
+            }
+            let text = self.args.normalizer.normalize(&topic[i].rhs);
+            let accepted = target.assess_with(
+                &topic[i].rhs,
+                &self.args.normalizer,
+                &self.args.typo_tolerance,
+            ) != Verdict::Wrong;
+            if !accepted && !seen.contains(&text) {
+                seen.push(text);
+                r.push(i);
+            }
+        }
+        r
+    }
+
+    /// Records the assessment in the progress table.
+    /// Returns the new distrust of the entry.
+    fn apply(&mut self, ent: usize, pass: bool, grade: Option<Grade>) -> Score {
+        match grade {
+            Some(grade) => self.pt.set_quality(ent, grade.quality()),
+            None => self.pt.set(ent, pass),
+        }
+        let entry = &mut self.pt.entries[ent];
+        if let SimMode::Leitner(la) = &self.args.mode {
+            let level = entry.leitner_box.unwrap_or(1);
+            entry.leitner_box = Some(if pass { (level + 1).min(la.boxes()) } else { 1 });
+        }
+        entry.distrust
+    }
+
+    /// Moves the state machine to the next message.
+    fn advance(&mut self, topic: &[TableEntry], pass: bool) -> UiMessage {
+        let inp = &mut Input {
+            pt: &mut self.pt,
+            args: &self.args,
+            rng: self.rng.as_mut(),
+            buried: &self.buried,
+        };
+        let r = self.state.next(inp, pass, 1);
+        if cfg!(sim_debug) {
+            eprintln!();
+            eprintln!();
+        }
+        let r = match (r.unwrap_or(TMessage::NothingDue), &self.args.questions) {
+            (TMessage::Assess(idx), QuestionMode::Choice(ca)) => {
+                let mut options = self.distractors(topic, idx, ca.distractors);
+                options.push(idx);
+                options.shuffle(&mut self.rng);
+                TMessage::Choose { idx, options }
+            }
+            (r, _) => r,
+        };
+        self.last_msg = Some(r.clone());
+        r
+    }
+
+    fn remember(&mut self, record: UndoRecord) {
+        self.history.push_back(record);
+        while self.history.len() > self.args.undo_limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Replaces the verdict of the last answer (e.g. when the user insists
+    /// that a formatting quirk was not a mistake).
+    /// The answer is re-applied to the state before it,
+    /// and the session continues as if the answer had the given outcome
+    /// (e.g. a wrong option overridden to pass is no longer a confusion).
+    /// Fails if the last action was not an answer.
+    pub fn override_last(
+        &mut self,
+        topic: &[TableEntry],
+        pass: bool,
+    ) -> Result<(UiMessage, Change), BadMessageError> {
+        let last = match self.history.back() {
+            Some(UndoRecord {
+                change: Some(change),
+                ..
+            }) => change.clone(),
+            _ => return Err(BadMessageError),
+        };
+        let record = self.history.pop_back().unwrap();
+        self.pt.restore_entry(&record.snapshot);
+        self.last_msg = record.last_msg.clone();
+        self.state = record.state.clone();
+        self.queued = None;
+        if let Some(chosen) = record.chosen.filter(|&x| x != last.idx) {
+            if pass {
+                self.confusions.remove(&(last.idx, chosen));
+            } else {
+                self.confusions.insert((last.idx, chosen));
+            }
+        }
+        let change = Change {
+            pass,
+            distrust: self.apply(last.idx, pass, None),
+            grade: None,
+            overridden: true,
+            ..last
+        };
+        self.remember(UndoRecord {
+            change: Some(change.clone()),
+            ..record
+        });
+        let message = self.advance(topic, pass);
+        let event = LogEvent::Override {
+            pass,
+            message: message.clone(),
+            change: change.clone(),
+        };
+        self.write_log(event, Some(change.idx));
+        Ok((message, change))
+    }
+
+    /// Reverts the last response to `Assess`: its effect on the progress table
+    /// and every message emitted since.
+    /// Returns the message which was answered; it is pending again.
+    /// Returns `None` if there is nothing to undo.
+    pub fn undo(&mut self) -> Option<UiMessage> {
+        let record = self.history.pop_back()?;
+        self.pt.restore_entry(&record.snapshot);
+        self.last_msg = record.last_msg;
+        self.state = record.state;
+        self.buried = record.buried;
+        self.queued = None;
+        let event = LogEvent::Undo {
+            message: self.last_msg.clone(),
+        };
+        self.write_log(event, Some(record.snapshot.idx));
+        self.last_msg.clone()
+    }
+
+    /// The message awaiting the user's response, if any.
+    pub fn pending(&self) -> Option<&UiMessage> {
+        self.last_msg.as_ref()
+    }
+
+    /// Takes a snapshot of the session, so that it can be resumed later
+    /// at the same pending message.
+    /// The progress table and the random number generator are not included.
+    pub fn save_state(&self) -> SimState {
+        SimState {
+            last_msg: self.last_msg.clone(),
+            state: self.state.clone(),
+            buried: self.buried.clone(),
+            queued: self.queued.clone(),
+            confusions: self.confusions.clone(),
+        }
+    }
+
+    /// Resumes a session saved by `save_state`.
+    /// Returns the pending message, which should be shown to the user again.
+    pub fn restore_state(&mut self, state: SimState) -> Option<UiMessage> {
+        self.last_msg = state.last_msg;
+        self.state = state.state;
+        self.buried = state.buried;
+        self.queued = state.queued;
+        self.confusions = state.confusions;
+        self.history.clear();
+        self.last_msg.clone()
+    }
+
+    /// Ends the session; the buried entries are available again.
     pub fn flush_state(&mut self) {
-        self.state = Main::new();
+        self.state = Self::initial_state(&self.args);
         self.last_msg = None;
+        self.history.clear();
+        self.buried.clear();
+        self.queued = None;
+        self.confusions.clear();
     }
 }
 
 pub struct Input<'b> {
     pt: &'b mut ProgressTable,
     args: &'b SimArgs,
+    rng: &'b mut dyn RngCore,
+    buried: &'b BTreeSet<usize>,
+}
+
+impl Input<'_> {
+    fn pop_unburied(&self, ents: &mut Vec<usize>) -> Option<usize> {
+        std::iter::from_fn(|| ents.pop()).find(|x| !self.buried.contains(x))
+    }
 }
 
 const MAXDEPTH: u16 = 30;
@@ -119,20 +710,51 @@ pub trait Domain {
     fn next<'b>(&mut self, inp: &mut Input<'b>, pass: bool, depth: u16) -> Option<UiMessage>;
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Bivariant<T, U> {
     V1(T),
     V2(U),
 }
 
-#[derive(Debug, Default)]
+impl<T: Domain, U: Domain> Domain for Bivariant<T, U> {
+    fn next<'b>(&mut self, inp: &mut Input<'b>, pass: bool, depth: u16) -> Option<UiMessage> {
+        match self {
+            Bivariant::V1(a) => a.next(inp, pass, depth),
+            Bivariant::V2(a) => a.next(inp, pass, depth),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Main {
     inner: Option<Bivariant<Assessment, Learning>>,
+    /// Whether the current session has emitted a message.
+    #[serde(default)]
+    emitted: bool,
+    /// Number of consecutive sessions which had no entries.
+    #[serde(default)]
+    empty: u8,
 }
 
 impl Main {
     pub fn new() -> Self {
-        Self { inner: None }
+        Self::default()
+    }
+
+    /// Replaces the finished session by the other one.
+    /// The round ends (`None`) once both sessions in a row had no entries.
+    fn switch<'b>(&mut self, inp: &mut Input<'b>, pass: bool, depth: u16) -> Option<UiMessage> {
+        self.empty = if self.emitted { 0 } else { self.empty + 1 };
+        self.emitted = false;
+        if self.empty >= 2 {
+            *self = Self::new();
+            return None;
+        }
+        self.inner = Some(match self.inner {
+            Some(Bivariant::V1(_)) => Bivariant::V2(Learning::new(inp)),
+            _ => Bivariant::V1(Assessment::new(inp)),
+        });
+        self.next(inp, pass, depth + 1)
     }
 }
 
@@ -148,19 +770,12 @@ impl Domain for Main {
                 self.inner = Some(Bivariant::V1(Assessment::new(inp)));
                 self.next(inp, pass, depth + 1)
             }
-            Some(Bivariant::V1(a)) => match a.next(inp, pass, depth + 1) {
-                None => {
-                    self.inner = Some(Bivariant::V2(Learning::new(inp)));
-                    self.next(inp, pass, depth + 1)
-                }
-                Some(b) => Some(b),
-            },
-            Some(Bivariant::V2(a)) => match a.next(inp, pass, depth + 1) {
-                None => {
-                    self.inner = Some(Bivariant::V1(Assessment::new(inp)));
-                    self.next(inp, pass, depth + 1)
+            Some(a) => match a.next(inp, pass, depth + 1) {
+                None => self.switch(inp, pass, depth),
+                Some(b) => {
+                    self.emitted = true;
+                    Some(b)
                 }
-                Some(b) => Some(b),
             },
         };
         if cfg!(sim_debug) {
@@ -171,7 +786,7 @@ impl Domain for Main {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Assessment {
     began: bool,
     ents: Vec<usize>,
@@ -179,17 +794,17 @@ pub struct Assessment {
 
 impl Assessment {
     pub fn new(inp: &mut Input) -> Self {
-        const ASSESS_SESSIONS: usize = 10;
-        let mut rng = thread_rng();
+        let n = inp.args.sessions.assess_size(inp.pt);
+        let rng = &mut *inp.rng;
         let ents = inp
             .pt
-            .select_random_entries(ASSESS_SESSIONS, true, || rng.gen::<f64>());
+            .select_random_entries_except(n, true, inp.buried, || rng.gen::<f64>());
         Self { ents, began: false }
     }
 }
 
 impl Domain for Assessment {
-    fn next<'b>(&mut self, _: &mut Input<'b>, _pass: bool, depth: u16) -> Option<UiMessage> {
+    fn next<'b>(&mut self, inp: &mut Input<'b>, _pass: bool, depth: u16) -> Option<UiMessage> {
         assert!(depth < MAXDEPTH);
         if cfg!(sim_debug) {
             let indent = unsafe { String::from_utf8_unchecked(vec![b'|'; depth as usize]) };
@@ -197,7 +812,7 @@ impl Domain for Assessment {
         }
         let r = {
             if self.began {
-                self.ents.pop().map(TMessage::Assess)
+                inp.pop_unburied(&mut self.ents).map(TMessage::Assess)
             } else {
                 self.began = true;
                 if self.ents.is_empty() {
@@ -215,7 +830,7 @@ impl Domain for Assessment {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Learning {
     ents: Vec<usize>,
     inner: Option<LearnSingle>,
@@ -223,10 +838,10 @@ pub struct Learning {
 
 impl Learning {
     pub fn new(inp: &mut Input) -> Self {
-        const LEARN_SESSIONS: usize = 10;
+        let n = inp.args.sessions.learn_size(inp.pt);
         let mut ents = inp
             .pt
-            .select_random_entries(LEARN_SESSIONS, false, || 0_f64);
+            .select_random_entries_except(n, false, inp.buried, || 0_f64);
         ents.reverse();
         Self { ents, inner: None }
     }
@@ -240,7 +855,7 @@ impl Domain for Learning {
             eprintln!("{} {:?}", indent, self);
         }
         let r = match &mut self.inner {
-            None => self.ents.pop().and_then(|tail| {
+            None => inp.pop_unburied(&mut self.ents).and_then(|tail| {
                 self.inner = Some(LearnSingle::new(tail));
                 self.next(inp, _pass, depth + 1)
             }),
@@ -257,7 +872,7 @@ impl Domain for Learning {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct LearnSingle {
     began: bool,
     head: Option<usize>,
@@ -287,14 +902,16 @@ impl Domain for LearnSingle {
             if !inp.args.classic {
                 self.stack.push(vhead);
             }
-            self.stack.extend(
-                inp.pt
-                    .select_random_entries(1, true, || thread_rng().gen::<f64>())
-                    .iter(),
-            );
+            let rng = &mut *inp.rng;
+            self.stack.extend(inp.pt.select_random_entries_except(
+                inp.args.sessions.rehearse,
+                true,
+                inp.buried,
+                || rng.gen::<f64>(),
+            ));
             Some(TMessage::Display(vhead))
         } else {
-            self.stack.pop().map(|tail| {
+            inp.pop_unburied(&mut self.stack).map(|tail| {
                 self.head = Some(tail);
                 TMessage::Assess(tail)
             })
@@ -306,3 +923,336 @@ impl Domain for LearnSingle {
         r
     }
 }
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Leitner {
+    began: bool,
+    ents: Vec<usize>,
+    last: Option<usize>,
+}
+
+impl Leitner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Collects the entries of the boxes due in the current round.
+    /// Rounds with no entries due are skipped.
+    fn select(inp: &mut Input, la: &LeitnerArgs) -> Vec<usize> {
+        let rounds = la.frequencies.iter().copied().max().unwrap_or(1).max(1);
+        for _ in 0..rounds {
+            let round = inp.pt.get_leitner_round();
+            let mut ents: Vec<usize> = inp
+                .pt
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| !inp.buried.contains(idx))
+                .filter(|(_, x)| {
+                    let level = x.leitner_box.unwrap_or(1).clamp(1, la.boxes());
+                    let freq = la.frequencies.get(level as usize - 1).copied().unwrap_or(1);
+                    round.is_multiple_of(freq.max(1))
+                })
+                .map(|(idx, _)| idx)
+                .collect();
+            if !ents.is_empty() {
+                ents.shuffle(&mut inp.rng);
+                return ents;
+            }
+            inp.pt.step_leitner_round();
+        }
+        Vec::new()
+    }
+}
+
+impl Domain for Leitner {
+    fn next<'b>(&mut self, inp: &mut Input<'b>, pass: bool, depth: u16) -> Option<UiMessage> {
+        assert!(depth < MAXDEPTH);
+        if cfg!(sim_debug) {
+            let indent = unsafe { String::from_utf8_unchecked(vec![b'|'; depth as usize]) };
+            eprintln!("{} {:?}", indent, self);
+        }
+        let la = match &inp.args.mode {
+            SimMode::Leitner(la) => la.clone(),
+            SimMode::Alternating => LeitnerArgs::default(),
+        };
+        let r = match self.last.take() {
+            Some(last) if !pass => Some(TMessage::Display(last)),
+            _ => match inp.pop_unburied(&mut self.ents) {
+                Some(idx) => {
+                    self.last = Some(idx);
+                    Some(TMessage::Assess(idx))
+                }
+                None => {
+                    if self.began {
+                        inp.pt.step_leitner_round();
+                    }
+                    self.began = true;
+                    self.ents = Self::select(inp, &la);
+                    if self.ents.is_empty() {
+                        None
+                    } else {
+                        Some(TMessage::NotifyAssessment)
+                    }
+                }
+            },
+        };
+        if cfg!(sim_debug) {
+            let indent = unsafe { String::from_utf8_unchecked(vec![b'|'; depth as usize]) };
+            eprintln!("{} {:?}", indent, r);
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::ent_ex::{ProgressTableView, ScoreArgs, Side};
+    use crate::sched::FsrsScheduler;
+    use std::sync::Arc;
+
+    fn topic(n: usize) -> Vec<TableEntry> {
+        (0..n)
+            .map(|i| TableEntry {
+                lhs: format!("q{}", i),
+                rhs: format!("a{}", i),
+                alternatives: Vec::new(),
+                side: Side::Forward,
+                cloze: None,
+            })
+            .collect()
+    }
+
+    fn table(topic: &[TableEntry]) -> ProgressTable {
+        let args = ScoreArgs {
+            degrade_factor: 0.8,
+            origin: Score(10000),
+            target: Score(100),
+        };
+        ProgressTable::new(Arc::pin(topic.to_vec()), args)
+    }
+
+    #[test]
+    fn buried_entries_do_not_hide_the_rest() {
+        let topic = topic(12);
+        let pt = table(&topic);
+        let mut sim = Simulation::with_seed(pt, SimArgs::default(), 0);
+        let mut asked = BTreeSet::new();
+        let mut action = Action::Continue;
+        for _ in 0..100 {
+            let (msg, _) = sim.next(&topic, action).ok().unwrap();
+            action = match msg {
+                TMessage::Assess(idx) if idx < 10 => Action::Bury,
+                TMessage::Assess(idx) => {
+                    asked.insert(idx);
+                    Action::Answer(topic[idx].rhs.clone())
+                }
+                _ => Action::Continue,
+            };
+            if asked.len() == 2 {
+                return;
+            }
+        }
+        panic!("entries 10 and 11 were never asked");
+    }
+
+    #[test]
+    fn round_ends_when_nothing_is_due() {
+        let topic = topic(3);
+        let mut pt = table(&topic);
+        pt.set_clock(Box::new(FixedClock(0)));
+        pt.set_scheduler(Box::new(FsrsScheduler::default()));
+        let mut sim = Simulation::with_seed(pt, SimArgs::default(), 0);
+        let mut action = Action::Continue;
+        for _ in 0..100 {
+            let (msg, _) = sim.next(&topic, action).ok().unwrap();
+            action = match msg {
+                TMessage::Assess(idx) => Action::Answer(topic[idx].rhs.clone()),
+                TMessage::NothingDue => break,
+                _ => Action::Continue,
+            };
+        }
+        assert!(matches!(sim.pending(), Some(TMessage::NothingDue)));
+        assert_eq!(sim.pt.get_unpassed_entries_count(), 0);
+        let (msg, _) = sim.next(&topic, Action::Continue).ok().unwrap();
+        assert!(matches!(msg, TMessage::NothingDue));
+        sim.pt.set_clock(Box::new(FixedClock(30 * 86400)));
+        let (msg, _) = sim.next(&topic, Action::Continue).ok().unwrap();
+        assert!(matches!(msg, TMessage::NotifyAssessment));
+    }
+
+    #[test]
+    fn leitner_rounds_leave_the_age_alone() {
+        let topic = topic(3);
+        let args = SimArgs {
+            mode: SimMode::Leitner(LeitnerArgs::default()),
+            ..SimArgs::default()
+        };
+        let mut sim = Simulation::with_seed(table(&topic), args, 0);
+        let mut action = Action::Continue;
+        for _ in 0..50 {
+            let (msg, _) = sim.next(&topic, action).ok().unwrap();
+            action = match msg {
+                TMessage::Assess(idx) => Action::Answer(topic[idx].rhs.clone()),
+                _ => Action::Continue,
+            };
+        }
+        let round = sim.pt.get_leitner_round();
+        assert!(round > 1);
+        assert_eq!(sim.pt.get_age(), 0);
+        let view = serde_json::to_string(&ProgressTableView::new(&sim.pt, &topic)).unwrap();
+        let view: ProgressTableView = serde_json::from_str(&view).unwrap();
+        assert_eq!(view.leitner_round, round);
+    }
+
+    /// Answers every `Assess` (or `Choose`) correctly until `until` holds.
+    fn run_until<F>(sim: &mut Simulation, topic: &[TableEntry], until: F) -> UiMessage
+    where
+        F: Fn(&UiMessage) -> bool,
+    {
+        let mut action = Action::Continue;
+        for _ in 0..100 {
+            let (msg, _) = sim.next(topic, action).ok().unwrap();
+            if until(&msg) {
+                return msg;
+            }
+            action = match msg {
+                TMessage::Assess(idx) => Action::Answer(topic[idx].rhs.clone()),
+                TMessage::Choose { idx, options } => {
+                    Action::Choose(options.iter().position(|&x| x == idx).unwrap())
+                }
+                TMessage::Reveal(_) => Action::Grade(Grade::Good),
+                _ => Action::Continue,
+            };
+        }
+        panic!("the expected message never came");
+    }
+
+    #[test]
+    fn continue_does_not_skip_a_question() {
+        let topic = topic(3);
+        for questions in [
+            QuestionMode::Typed,
+            QuestionMode::SelfGraded,
+            QuestionMode::Choice(ChoiceArgs::default()),
+        ] {
+            let args = SimArgs {
+                questions,
+                ..SimArgs::default()
+            };
+            let self_graded = matches!(args.questions, QuestionMode::SelfGraded);
+            let mut sim = Simulation::with_seed(table(&topic), args, 0);
+            let mut msg = run_until(&mut sim, &topic, |x| {
+                matches!(x, TMessage::Assess(_) | TMessage::Choose { .. })
+            });
+            if self_graded {
+                msg = sim.next(&topic, Action::Continue).ok().unwrap().0;
+                assert!(matches!(msg, TMessage::Reveal(_)));
+            }
+            assert!(sim.next(&topic, Action::Continue).is_err());
+            assert_eq!(sim.pending(), Some(&msg));
+            assert_eq!(sim.pt.get_unpassed_entries_count(), 3);
+        }
+    }
+
+    fn answer(sim: &mut Simulation, topic: &[TableEntry]) -> UiMessage {
+        let idx = match sim.pending() {
+            Some(TMessage::Assess(idx)) => *idx,
+            x => panic!("not a question: {:?}", x),
+        };
+        sim.next(topic, Action::Answer(topic[idx].rhs.clone()))
+            .ok()
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn undo_restores_the_state_before_the_answer() {
+        let topic = topic(3);
+        let mut sim = Simulation::with_seed(table(&topic), SimArgs::default(), 0);
+        let question = run_until(&mut sim, &topic, |x| matches!(x, TMessage::Assess(_)));
+        let idx = match question {
+            TMessage::Assess(idx) => idx,
+            _ => unreachable!(),
+        };
+        let before = sim.pt.entries[idx];
+        let unpassed = sim.pt.get_unpassed_entries_count();
+        let reply = answer(&mut sim, &topic);
+        assert!(sim.pt.entries[idx].pass);
+        sim.next(&topic, Action::Continue).ok().unwrap();
+        assert_eq!(sim.undo(), Some(question.clone()));
+        assert_eq!(sim.pending(), Some(&question));
+        assert_eq!(sim.pt.entries[idx].distrust, before.distrust);
+        assert_eq!(sim.pt.entries[idx].pass, before.pass);
+        assert_eq!(sim.pt.get_unpassed_entries_count(), unpassed);
+        assert_eq!(answer(&mut sim, &topic), reply);
+    }
+
+    #[test]
+    fn undo_keeps_only_the_last_answers() {
+        let topic = topic(3);
+        let args = SimArgs {
+            undo_limit: 1,
+            ..SimArgs::default()
+        };
+        let mut sim = Simulation::with_seed(table(&topic), args, 0);
+        for _ in 0..2 {
+            if !matches!(sim.pending(), Some(TMessage::Assess(_))) {
+                run_until(&mut sim, &topic, |x| matches!(x, TMessage::Assess(_)));
+            }
+            answer(&mut sim, &topic);
+        }
+        assert!(sim.undo().is_some());
+        assert!(sim.undo().is_none());
+    }
+
+    #[test]
+    fn override_to_pass_moves_learning_on() {
+        let topic = topic(3);
+        let mut sim = Simulation::with_seed(table(&topic), SimArgs::default(), 0);
+        let idx = match run_until(&mut sim, &topic, |x| matches!(x, TMessage::Assess(_))) {
+            TMessage::Assess(idx) => idx,
+            _ => unreachable!(),
+        };
+        let (msg, change) = sim
+            .next(&topic, Action::Answer(String::from("wrong")))
+            .ok()
+            .unwrap();
+        assert_eq!(msg, TMessage::Display(idx));
+        assert!(!change.unwrap().pass);
+        let (msg, change) = sim.override_last(&topic, true).ok().unwrap();
+        assert!(change.pass && change.overridden);
+        assert_ne!(msg, TMessage::Display(idx));
+        assert!(sim.pt.entries[idx].pass);
+        let (msg, change) = sim.override_last(&topic, false).ok().unwrap();
+        assert!(!change.pass && change.overridden);
+        assert_eq!(msg, TMessage::Display(idx));
+        assert!(!sim.pt.entries[idx].pass);
+    }
+
+    #[test]
+    fn override_to_pass_forgets_the_confusion() {
+        let topic = topic(4);
+        let args = SimArgs {
+            questions: QuestionMode::Choice(ChoiceArgs::default()),
+            ..SimArgs::default()
+        };
+        let mut sim = Simulation::with_seed(table(&topic), args, 0);
+        let msg = run_until(&mut sim, &topic, |x| matches!(x, TMessage::Choose { .. }));
+        let (idx, wrong) = match msg {
+            TMessage::Choose { idx, options } => {
+                (idx, options.iter().position(|&x| x != idx).unwrap())
+            }
+            _ => unreachable!(),
+        };
+        sim.next(&topic, Action::Choose(wrong)).ok().unwrap();
+        assert_eq!(sim.confusions.len(), 1);
+        sim.override_last(&topic, true).ok().unwrap();
+        assert!(sim.confusions.is_empty());
+        sim.override_last(&topic, false).ok().unwrap();
+        assert!(sim.confusions.iter().all(|&(x, _)| x == idx));
+        assert_eq!(sim.confusions.len(), 1);
+    }
+}